@@ -0,0 +1,39 @@
+//! Runs the upload stand-in from `bender_worker::work::standin` locally, so \
+//! frame uploads (plain and chunked) can be tested without a flaskbender:
+//!
+//! ```text
+//! cargo run --example upload_standin -- [directory] [port] [access_key:secret_key]
+//! ```
+//!
+//! Then point the `bender_url` of an independent worker (or the `endpoint` of \
+//! its S3 sink) at the printed URL. If keys are given, S3 requests have to be \
+//! signed with them.
+
+extern crate bender_worker;
+
+use std::env;
+use bender_worker::work::standin::StandIn;
+
+fn main(){
+    let mut args = env::args().skip(1);
+    let directory = args.next().unwrap_or_else(|| "standin-uploads".to_string());
+    let port = args.next().unwrap_or_else(|| "5000".to_string());
+
+    let credentials = args.next();
+
+    let standin = match StandIn::bind(format!("127.0.0.1:{}", port), directory.as_str()){
+        Ok(standin) => match credentials.as_ref().and_then(|c| c.find(':').map(|i| c.split_at(i))){
+            Some((access_key, secret_key)) => standin.with_s3_credentials(access_key, &secret_key[1..]),
+            None => standin
+        },
+        Err(err) => {
+            eprintln!(" ✖ [STANDIN] Error: Couldn't start on port {}: {}", port, err);
+            std::process::exit(1);
+        }
+    };
+    match standin.url(){
+        Ok(url) => println!(" @ [STANDIN] Storing uploads to {} at {}", directory, url),
+        Err(err) => eprintln!(" ✖ [STANDIN] Error: {}", err)
+    }
+    standin.serve();
+}
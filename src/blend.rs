@@ -1,10 +1,12 @@
 use work::blendfiles::Blendfile;
+use work::downloads::DownloadProgress;
 
 /// A Enum Variant that encodes the varios states a Blendfile can be in.
 #[derive(Debug, Clone)]
 pub enum Blend{
     Optimized(Blendfile),
     Downloaded(Blendfile),
+    Downloading{ progress: DownloadProgress },
     None
 }
 
@@ -30,6 +32,7 @@ impl Blend{
     pub fn is_some(&self) -> bool{
         match self{
             Blend::None => false,
+            Blend::Downloading{..} => false,
             _ => true
         }
     }
@@ -37,10 +40,18 @@ impl Blend{
     pub fn is_downloaded(&self) -> bool{
         match self{
             Blend::None => false,
+            Blend::Downloading{..} => false,
             _ => true
         }
     }
 
+    pub fn is_downloading(&self) -> bool{
+        match self{
+            Blend::Downloading{..} => true,
+            _ => false
+        }
+    }
+
     pub fn is_optimized(&self) -> bool{
         match self{
             Blend::Optimized(_) => true,
@@ -48,10 +59,20 @@ impl Blend{
         }
     }
 
+    /// Return the Blendfile, if it has been downloaded
+    pub fn blendfile_mut(&mut self) -> Option<&mut Blendfile>{
+        match self{
+            Blend::Downloaded(b) => Some(b),
+            Blend::Optimized(b) => Some(b),
+            _ => None
+        }
+    }
+
     pub fn unwrap(self) -> Blendfile{
         match self{
             Blend::Downloaded(b) => b,
             Blend::Optimized(b) => b,
+            Blend::Downloading{..} => panic!("Called `Blend::unwrap()` on a `Blend::Downloading` value"),
             Blend::None => panic!("Called `Blend::unwrap()` on a `Blend::None` value")
         }
     }
@@ -1,7 +1,13 @@
 use crate::*;
-use config::WorkerConfig;
+use config::{WorkerConfig, BrokerConfig, GenResult};
 use bender_mq::BenderMQ;
+use amqp::{Session, Options, AMQPScheme};
 use std::fs::DirBuilder;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use work::capabilities::Capabilities;
+use work::transport::RoutedChannel;
+use work::blenders;
 
 #[cfg(unix)]
 use std::os::unix::fs::DirBuilderExt;
@@ -94,6 +100,62 @@ pub fn benderurl(args: &Args){
 
 
 
+/// Returns true if a previous run left a journal with Tasks to resume behind
+pub fn has_journal(args: &Args) -> bool{
+    let config = match get_paths(){
+        (Some(a), Some(b)) => config::get_config(a, b, &args),
+        (None, None) => config::get_config(PathBuf::from(""), PathBuf::from(""), &args),
+        (_, _) => panic!("This shouldn't have happened. This was meant to be an unreachable arm!")
+    };
+    match config{
+        Ok(config) => work::journal::has_journal(&config),
+        Err(_) => false
+    }
+}
+
+
+
+
+/// Print the render logs of the task with the given id (or id prefix). Rotated \
+/// logs are printed first, so the output is in chronological order
+pub fn logs(args: &Args){
+    let config = match get_paths(){
+        (Some(a), Some(b)) => config::get_config(a, b, &args),
+        (None, None) => config::get_config(PathBuf::from(""), PathBuf::from(""), &args),
+        (_, _) => panic!("This shouldn't have happened. This was meant to be an unreachable arm!")
+    };
+    match config{
+        Ok(config) => {
+            match work::logs::find_logs(&config.outpath, args.arg_task_id.as_str()){
+                Ok(ref paths) if paths.is_empty() => {
+                    errmsg(format!("Found no render log for a task [{}] in {}", args.arg_task_id, config.outpath.to_string_lossy()));
+                    process::exit(1);
+                },
+                Ok(paths) => {
+                    for path in paths{
+                        let mut rotated: Vec<PathBuf> = (1..config.log_rotations+1)
+                                                            .map(|n| work::logs::rotated_path(&path, n))
+                                                            .filter(|p| p.is_file())
+                                                            .collect();
+                        rotated.reverse();
+                        rotated.push(path);
+                        for p in rotated{
+                            println!("{}", format!("==> {} <==", p.to_string_lossy()).bold());
+                            match fs::read_to_string(&p){
+                                Ok(contents) => print!("{}", contents),
+                                Err(err) => eprintln!("{}", format!(" ✖ Error: Couldn't read {}: {}", p.to_string_lossy(), err).red())
+                            }
+                        }
+                    }
+                },
+                Err(err) => eprintln!("{}", format!(" ✖ Error: Couldn't search for logs in {}: {}", config.outpath.to_string_lossy(), err).red())
+            }
+        },
+        Err(err) => eprintln!("{}", format!(" ✖ Error: {}", err).red())
+    }
+}
+
+
 
 /// Delete the workers files (runs either clean_gentle() or clean_force())
 pub fn clean(args: &Args){
@@ -167,7 +229,8 @@ pub fn clean_force(args: &Args, config: &WorkerConfig){
 }
 
 /// Delete the contents of the blendfiles directory specified in the config. 
-/// This only deletes files whose extension starts with .blend
+/// This only deletes files whose extension starts with .blend (and unfinished 
+/// downloads ending on .blend.part)
 fn delete_blendfiles(config: &WorkerConfig){
     let p = config.blendpath.clone();
     match fs::read_dir(&p){
@@ -176,7 +239,8 @@ fn delete_blendfiles(config: &WorkerConfig){
                 match entry{
                     Ok(e) => {
                         let path = e.path();
-                        if path.is_file() && path.extension().is_some() && path.extension().unwrap().to_string_lossy().to_lowercase().starts_with("blend"){
+                        let is_part = path.to_string_lossy().to_lowercase().ends_with(".blend.part");
+                        if path.is_file() && (is_part || (path.extension().is_some() && path.extension().unwrap().to_string_lossy().to_lowercase().starts_with("blend"))){
                             match fs::remove_file(&path) {
                                 Ok(_) => println!("{}", format!(" ✔ Deleted blendfile at {}", path.to_string_lossy()).green()),
                                 Err(err) => eprintln!("{}", format!(" ✖ Error while deleting {}: {}", path.to_string_lossy(), err).red())
@@ -270,11 +334,6 @@ pub fn run(args: &Args) {
     // Get a valid application save path depending on the OS
     scrnmsg(format!("\n{x} BENDER-WORKER {x}", x="=".repeat((width()-15)/2)));
 
-    if !system::blender_in_path(){
-        errmsg("Found no 'blender' command in the PATH. Make sure it is installed and in PATH environment variable".to_string());
-        process::exit(1);
-    }
-    
     match get_paths(){
         (Some(a), Some(b)) => run_worker(args, a, b),
         (None, None) => run_worker(args, PathBuf::from(""), PathBuf::from("")),
@@ -313,16 +372,22 @@ pub fn run_worker(args: &Args, app_configpath: PathBuf, app_cachepath: PathBuf){
                     config.outpath.to_string_lossy() );
                 process::exit(1);
             }
+            // Every configured Blender has to run (or the one in PATH if none is configured)
+            for install in blenders::installs(&config.blenders).iter(){
+                if !system::blender_available(&install.path){
+                    errmsg(format!("Couldn't run Blender {} at {}. Make sure it is installed and in PATH environment variable or fix the path in the config", install.name, install.path.to_string_lossy()));
+                    process::exit(1);
+                }
+            }
             scrnmsg(format!("Running in Independent Mode. Using the config at {}", app_configpath.to_string_lossy()));
             // We sucessfully created a config file, let's go ahead
             scrnmsg(format!("This Worker has the ID:             [{}]", config.id));
 
-            // For now. TODO: discover this on the bender.hfbk.net
-            let url = "amqp://localhost//".to_string();
-            scrnmsg(format!("Listening on for AMQP traffic at:   {}", url));
+            scrnmsg(format!("Listening on for AMQP traffic at:   {}", config.broker.display_url()));
             scrnmsg(format!("Storing jobs at:                    {}", config.blendpath.to_string_lossy()));
-            let mut channel = Channel::open_default_channel()
-                                      .unwrap_or_else(|_| panic!("{}", " ✖ [WORKER] Error: Couldn't aquire channel".to_string().red()));
+            // Keep the session around for as long as the channel is used
+            let (_session, mut channel) = open_channel(&config.broker)
+                                      .unwrap_or_else(|err| panic!("{}", format!(" ✖ [WORKER] Error: Couldn't aquire channel: {}", err).red()));
 
             // Declare a Work exchange
             channel.create_work_queue()
@@ -331,6 +396,14 @@ pub fn run_worker(args: &Args, app_configpath: PathBuf, app_cachepath: PathBuf){
             // Declare a topic exchange
             channel.declare_worker_exchange()
                    .unwrap_or_else(|_| panic!("{}", " ✖ [WORKER] Error: Declaration of worker-topic exchange failed".to_string().red()));
+
+            // Take Tasks from the queues matching what this worker can do
+            let capabilities = Capabilities::detect(&config);
+            capabilities.print();
+            let mut channel = RoutedChannel::new(channel, capabilities.queues.clone());
+            channel.declare_queues()
+                   .unwrap_or_else(|err| panic!("{}", format!(" ✖ [WORKER] Error: Declaration of capability queues failed: {}", err).red()));
+            capabilities.publish(&mut channel);
             
 
             // Print the space left on the Worker Machine (at the path of the Application Data)
@@ -338,12 +411,65 @@ pub fn run_worker(args: &Args, app_configpath: PathBuf, app_cachepath: PathBuf){
 
             let mut work = Work::new(config.clone());
 
+            // Pick up finished Tasks a previous run couldn't upload or report
+            work.restore_journal();
+
             scrnmsg("v".repeat(width()).to_string());
                 
 
+            // Count the received SIGTERM/SIGINT signals. The first one starts a \
+            // graceful shutdown, a second one kills running renders right away
+            let signals = Arc::new(AtomicUsize::new(0));
+            let handler_signals = signals.clone();
+            if let Err(err) = ctrlc::set_handler(move || { handler_signals.fetch_add(1, Ordering::SeqCst); }){
+                errmsg(format!("Couldn't set up the signal handler, graceful shutdown is not available: {}", err));
+            }
+
             loop{
-                work.update(&mut channel);
+                match signals.load(Ordering::SeqCst){
+                    0 => work.update(&mut channel),
+                    n => {
+                        let kill = n > 1 || config.shutdown_mode.is_kill();
+                        if work.shutdown(kill, &mut channel){
+                            break;
+                        }
+                    }
+                }
             }
         }   
     }
-}
\ No newline at end of file
+}
+
+
+
+/// Open a session to the broker described in the given BrokerConfig and \
+/// return it together with a channel on it
+pub fn open_channel(broker: &BrokerConfig) -> GenResult<(Session, Channel)>{
+    let (username, password) = broker.credentials()?;
+    if broker.tls{
+        // The amqp crate has no TLS settings in its Options, it verifies \
+        // against OpenSSLs default locations, which SSL_CERT_FILE overrides
+        if let Some(ref ca) = broker.tls_ca{
+            if !ca.is_file(){
+                return Err(From::from(format!("Didn't find the CA certificate for the broker at {}", ca.to_string_lossy())));
+            }
+            env::set_var("SSL_CERT_FILE", ca);
+        }
+        if !broker.tls_verify{
+            return Err(From::from("The amqp client can't skip the verification of the brokers certificate, set tls_ca to its CA certificate instead"));
+        }
+    }
+    let scheme = if broker.tls { AMQPScheme::AMQPS } else { AMQPScheme::AMQP };
+    let options = Options{
+        host:     broker.host.clone(),
+        port:     broker.port,
+        vhost:    broker.vhost.clone(),
+        login:    username,
+        password,
+        scheme,
+        .. Default::default()
+    };
+    let mut session = Session::new(options)?;
+    let channel = session.open_channel(1)?;
+    Ok((session, channel))
+}
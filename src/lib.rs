@@ -20,6 +20,7 @@ extern crate app_dirs;
 extern crate serde;
 extern crate fs2;
 extern crate serde_derive;
+extern crate serde_json;
 extern crate uuid;
 extern crate amqp;
 extern crate chrono;
@@ -31,7 +32,11 @@ extern crate toml;
 extern crate docopt;
 extern crate colored;
 extern crate console;
+extern crate ctrlc;
 extern crate reqwest;
+extern crate sha2;
+extern crate hmac;
+extern crate percent_encoding;
 
 #[cfg(unix)]
 extern crate users;
@@ -89,6 +94,7 @@ Usage:
   bender-worker get blendpath
   bender-worker get id
   bender-worker get benderurl
+  bender-worker logs <task-id>
   bender-worker (-h | --help)
   bender-worker --version
 
@@ -115,6 +121,8 @@ pub struct Args {
     cmd_clean: bool,
     cmd_blendfiles: bool,
     cmd_frames: bool,
+    cmd_logs: bool,
+    arg_task_id: String,
 }
 
 
@@ -138,13 +146,22 @@ pub fn run_main(){
     // Read the config (if there is one) and get the bender url
     }else if args.cmd_get && args.cmd_benderurl{
         command::benderurl(&args);
+    // Read the config (if there is one) and print the render log of a task
+    }else if args.cmd_logs{
+        command::logs(&args);
     // Read the config (if there is one) and get the bender url
     }else if args.cmd_clean{
         command::clean(&args);
     }else{
-        // Force clean the directories to avoid long time clutter
-        args.flag_force = true;
-        command::clean(&args);
+        // Force clean the directories to avoid long time clutter, unless a \
+        // previous run left finished Tasks in the journal, whose frames and \
+        // blendfiles are still needed
+        if command::has_journal(&args){
+            notemsg("Found unfinished work in the journal, skipping the cleanup");
+        }else{
+            args.flag_force = true;
+            command::clean(&args);
+        }
         // Then run
         command::run(&args);
     }
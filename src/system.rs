@@ -2,8 +2,10 @@ use ::*;
 
 
 
-pub fn blender_in_path() -> bool{
-    match process::Command::new("blender").arg("--version").output() {
+/// Check whether the given blender executable (e.g. `blender` from PATH) can \
+/// be run and print its version
+pub fn blender_available(blender: &Path) -> bool{
+    match process::Command::new(blender).arg("--version").output() {
         Ok(s) => {
             let blender_version = String::from_utf8_lossy(&s.stdout).to_string();
             scrnmsg(format!("Using Blender version: {}", blender_version.trim()));
@@ -12,10 +14,10 @@ pub fn blender_in_path() -> bool{
         },
         Err(e) => {
             if let std::io::ErrorKind::NotFound = e.kind() {
-                eprintln!(" ✖ [WORKER] Blender is not installed or not in PATH environment variable: {}", e);
+                eprintln!(" ✖ [WORKER] Blender at {} is not installed or not in PATH environment variable: {}", blender.to_string_lossy(), e);
                 false
             } else {
-                eprintln!(" ✖ [WORKER] Blender --version returned Error: {}", e);
+                eprintln!(" ✖ [WORKER] {} --version returned Error: {}", blender.to_string_lossy(), e);
                 false
             }
         }, 
@@ -63,3 +65,59 @@ pub fn print_space_warning<P>(p: P, limit: u64) where P: Into<PathBuf>{
         }
     }
 }
+
+
+
+/// Return the version of the given blender executable (e.g. `2.80` for \
+/// `Blender 2.80 (sub 75)`), without printing anything
+pub fn blender_version(blender: &Path) -> Option<String>{
+    let output = process::Command::new(blender).arg("--version").output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    stdout.lines()
+          .find(|line| line.trim_start().starts_with("Blender "))
+          .and_then(|line| line.split_whitespace().nth(1))
+          .map(|version| version.to_string())
+}
+
+
+
+/// Return the modules of the add-ons enabled in the user preferences of the \
+/// given blender executable. This starts Blender in the background, so it takes \
+/// a moment
+pub fn blender_addons(blender: &Path) -> Vec<String>{
+    // bpy.context.user_preferences was renamed to bpy.context.preferences in 2.80
+    let expression = "import bpy; p = getattr(bpy.context, 'preferences', None) or bpy.context.user_preferences; print('BENDER-ADDONS:' + ','.join(sorted(a.module for a in p.addons)))";
+    match process::Command::new(blender).args(&["-b", "--python-expr", expression]).output(){
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+                          .lines()
+                          .find(|line| line.starts_with("BENDER-ADDONS:"))
+                          .map(|line| line["BENDER-ADDONS:".len()..].split(',')
+                                                                    .filter(|addon| !addon.is_empty())
+                                                                    .map(|addon| addon.to_string())
+                                                                    .collect())
+                          .unwrap_or_default(),
+        Err(err) => {
+            eprintln!(" ✖ [WORKER] Error: Couldn't list the Blender add-ons: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+
+
+/// Return the number of CPUs available to the worker
+pub fn cpu_count() -> usize{
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+
+
+/// Return the total memory of the machine in bytes (only on Linux)
+pub fn memory_bytes() -> Option<u64>{
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo.lines()
+           .find(|line| line.starts_with("MemTotal:"))
+           .and_then(|line| line.split_whitespace().nth(1))
+           .and_then(|kilobytes| kilobytes.parse::<u64>().ok())
+           .map(|kilobytes| kilobytes * 1024)
+}
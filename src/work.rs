@@ -5,7 +5,6 @@ use ::*;
 use blend::Blend;
 use config::WorkerConfig;
 use bender_job::{Task, History};
-use bender_mq::BenderMQ;
 use std::collections::HashMap;
 use chrono::{Utc, DateTime};
 
@@ -17,27 +16,74 @@ pub mod requests;
 pub mod taskmanagment;
 pub mod optimize;
 pub mod ratelimit;
+pub mod transport;
+pub mod slots;
+pub mod render;
+pub mod progress;
+pub mod logs;
+pub mod retry;
+pub mod shutdown;
+pub mod journal;
+pub mod cache;
+pub mod downloads;
+pub mod uploads;
+pub mod chunked;
+pub mod standin;
+pub mod sinks;
+pub mod client;
+pub mod jobstatus;
+pub mod cancel;
+pub mod pause;
+pub mod capabilities;
+pub mod blenders;
+pub mod hooks;
+pub mod report;
+pub mod preflight;
 
 use ratelimit::RateLimiter;
+use transport::Transport;
+use slots::Slot;
+use cache::BlendCache;
+use downloads::DownloadPool;
+use optimize::Optimizer;
+use client::BenderClient;
+use jobstatus::JobState;
+use retry::Failure;
+use sinks::FrameSink;
 
 
 
 
 /// The Work struct holds the current configuration, tasks structs, as well as a\
-/// history and a Hashmap with blendfiles.
+/// history and a Hashmap with blendfiles. Tasks that are being rendered live \
+/// in one of the render `slots`.
 #[derive(Debug)]
 pub struct Work{
     pub config: WorkerConfig,
     pub tasks: Vec<Task>,
-    pub current: Option<Task>,
+    pub slots: Vec<Slot>,
     pub history: History,
     pub blendfiles: HashMap<String, Blend>,
-    pub parent_jobs: HashMap<String, String>,
-    command: Option<std::process::Child>,
+    pub cache: BlendCache,
+    pub downloads: DownloadPool,
+    pub optimizer: Optimizer,
+    pub client: BenderClient,
+    /// Where the frames of finished Tasks go, None if the config is broken
+    pub sink: Option<Box<FrameSink>>,
+    pub parent_jobs: HashMap<String, JobState>,
+    /// Jobs whose blendfile failed the hook pipeline or the pre-flight \
+    /// validation and why
+    pub rejected_jobs: HashMap<String, (Failure, String)>,
+    /// Tasks handed back because their job was paused and whether they had \
+    /// been redelivered before
+    paused_hand_backs: HashMap<String, bool>,
     last_heartbeat: Option<DateTime<Utc>>,
     last_download: RateLimiter,
     last_status: RateLimiter,
-    last_upload: RateLimiter
+    last_upload: RateLimiter,
+    shutting_down: bool,
+    journal_path: PathBuf,
+    last_journal: Option<String>
 }
 
 
@@ -49,18 +95,40 @@ impl Work{
 
     /// Create a new task with a given config
     pub fn new(config: WorkerConfig) -> Self{
+        let slots = Slot::many(config.render_slots);
+        let journal_path = journal::journal_path(&config);
+        let cache = BlendCache::open(&config.blendpath);
+        let downloads = DownloadPool::new(config.download_workers);
+        let optimizer = Optimizer::new();
+        let client = BenderClient::new(&config);
+        let sink = match sinks::from_config(&config){
+            Ok(sink) => Some(sink),
+            Err(err) => {
+                errrun(format!("Couldn't set up the frame sink: {}", err));
+                None
+            }
+        };
         Work{
             config,
             tasks: Vec::<Task>::new(),
-            current: None,
+            slots,
             history: History::new(),
             blendfiles: HashMap::<String, Blend>::new(),
-            parent_jobs: HashMap::<String, String>::new(),
-            command: None,
+            cache,
+            downloads,
+            optimizer,
+            client,
+            sink,
+            parent_jobs: HashMap::<String, JobState>::new(),
+            rejected_jobs: HashMap::<String, (Failure, String)>::new(),
+            paused_hand_backs: HashMap::<String, bool>::new(),
             last_heartbeat: None,
             last_download: RateLimiter::new(),
             last_status: RateLimiter::new(),
-            last_upload: RateLimiter::new()
+            last_upload: RateLimiter::new(),
+            shutting_down: false,
+            journal_path,
+            last_journal: None
         }
     }
 
@@ -74,17 +142,24 @@ impl Work{
 
 
     /// Runs every loop and updates everything. This is the meat of the \
-    /// business logic for the worker.
-    pub fn update(&mut self, channel: &mut Channel){
+    /// business logic for the worker. Tasks are received from and events are \
+    /// posted to the given transport (e.g. a amqp `Channel`)
+    pub fn update<T>(&mut self, transport: &mut T) where T: Transport{
         // Add new tasks only if we don't exceed the number of tasks definied \
         // in the workload setting
-        self.get_tasks(channel);
+        self.get_tasks(transport);
         // self.print_self("After get_tasks()");
 
         // Update each unique parent job status for all the Tasks
         self.update_parent_job_status();
         // self.print_self("After update_parent_job_status()");
 
+        // Kill and drop everything that belongs to canceled jobs
+        self.cancel_jobs(transport);
+
+        // Hand back the Tasks of paused jobs (if configured)
+        self.hand_back_paused(transport);
+
         // Get the blendfile from the server only if there are 
         // tasks that actually need one
         self.get_blendfiles();
@@ -96,34 +171,44 @@ impl Work{
         // self.print_self("After construct_commands()");
 
         // Optimize Blendfiles for local consumtion
-        self.optimize_blendfiles();
+        self.optimize_blendfiles(transport);
+
+        // Check the optimized blendfiles against the policy of this worker \
+        // and reject the Tasks of jobs that can't be rendered
+        self.preflight_blendfiles(transport);
 
-        // Update who the current Task is ("self.current")
-        self.select_next_task(channel);
+        // Move the next Tasks into free render slots ("self.slots")
+        self.select_next_task(transport);
         // self.print_self("After select_next_task()");
 
-        // Dispatch a Command for the current Task ("self.current")
-        self.run_command(channel);
+        // Dispatch a Command for each Task in a slot and check on running ones
+        self.run_command(transport);
         // self.print_self("After run_command()");
 
         // Get the filesize and hash for the rendered frames of a Task
-        self.stat_finished(channel);
+        self.stat_finished(transport);
         // self.print_self("After stat_finished()");
 
         // Upload the finished files
-        self.upload_finished(channel);
+        self.upload_finished(transport);
         // self.print_self("After upload_finished()");
 
         // Cleanup finished blendfiles
         self.cleanup_blendfiles();
         // self.print_self("After cleanup_blendfiles()");
 
+        // Evict unused blendfiles from the cache if the disk runs full
+        self.evict_blendfiles();
+
         // Cleanup rendered and uploaded frames
         self.cleanup_frames();
         // self.print_self("After cleanup_frames()");
 
         // Send a heart beat to the qu to signal you are alive
-        self.beat_heart(channel);
+        self.beat_heart(transport);
+
+        // Persist the state, so a restarted worker can resume it
+        self.write_journal();
 
         // Don't spin out of control if there are no Tasks
         self.sleep();
@@ -135,13 +220,21 @@ impl Work{
     fn print_self<S>(&self, note: S) where S:Into<String>{
         if self.has_task(){
             let note = note.into();
-            let current = match self.current{
-                Some(ref current) => format_task(&current),
-                None => "None".to_string()
-            };
+            let slots: Vec<String> = self.slots.iter()
+                                    .map(|slot| match slot.task{
+                                        Some(ref task) => format_task(&task),
+                                        None => "None".to_string()
+                                    })
+                                    .collect();
 
             let p: Vec<String> = self.parent_jobs.iter()
-                                    .map(|(id, status)| format!("[{}]: {}", &id[..6], &status[..]))
+                                    .map(|(id, status)| {
+                                        match self.blendfiles.get(id){
+                                            Some(Blend::Downloading{ progress }) => format!("[{}]: {} (Downloading {})", &id[..6], status, downloads::format_progress(progress)),
+                                            Some(Blend::Downloaded(_)) if self.optimizer.is_optimizing(id) => format!("[{}]: {} (Optimizing)", &id[..6], status),
+                                            _ => format!("[{}]: {}", &id[..6], status)
+                                        }
+                                    })
                                     .collect();
         
             println!("\n");
@@ -151,25 +244,19 @@ impl Work{
             println!("\n");
             println!("        {}", note.yellow());
             println!("        {}", "─────────────────────────────────┬────────────────────────────".yellow());
-            println!("        {}", format!(" Tasks ({})                       │ Current", self.tasks.len()).yellow());
+            println!("        {}", format!(" Tasks ({})                       │ Slots ({})", self.tasks.len(), self.slots.len()).yellow());
             println!("        {}", "─────────────────────────────────┼────────────────────────────".yellow());
-            if self.tasks.is_empty(){
-                println!("        {}", format!("                                │ {current}", 
-                            current=current
-                            ).yellow());
-            }else{
-                for (i, task) in self.tasks.iter().enumerate(){
-                    if i == 0{
-                        println!("        {}", format!(" {task}     │ {current}", 
-                            task=format_task(&task),
-                            current=current
-                            ).yellow());
-                    }else{
-                        println!("        {}", format!(" {task}     │ ", 
-                            task=format_task(&task)
-                            ).yellow());
-                    }
-                }
+            let rows = std::cmp::max(self.tasks.len(), slots.len());
+            for i in 0..rows{
+                let task = match self.tasks.get(i){
+                    Some(task) => format_task(&task),
+                    None => " ".repeat(27)
+                };
+                let slot = slots.get(i).cloned().unwrap_or_default();
+                println!("        {}", format!(" {task}     │ {slot}", 
+                    task=task,
+                    slot=slot
+                    ).yellow());
             }
             println!("        {}", "─────────────────────────────────┴────────────────────────────".yellow());
             println!("\n");
@@ -182,10 +269,10 @@ impl Work{
 
 
 
-    /// Send a heartbeat message to bender-worker via rabbitmq as a life sign
+    /// Send a heartbeat message to bender-worker via the transport as a life sign
     /// The heartbeat is rate limited and will only beat if the specified has \
     /// passed
-    fn beat_heart(&mut self, channel: &mut Channel) {
+    fn beat_heart<T>(&mut self, transport: &mut T) where T: Transport{
         // Determine whether the heart should beat
         let should_beat = match self.last_heartbeat{
             Some(time) => {
@@ -199,7 +286,7 @@ impl Work{
         // or it was the first one.
         if should_beat{
             let routing_key = format!("heart.{}", self.config.id);
-            channel.worker_post(routing_key, Vec::new());
+            transport.post_event(routing_key, Vec::new());
             self.last_heartbeat = Some(Utc::now());
         }
     }
@@ -215,4 +302,84 @@ fn format_task(task: &Task) -> String{
                     task_id=&task.id[..6],
                     short=task.command.short(),
                     status=format!("{:<9}", format!("{:?}",task.status).replace("\"", "")))
-}
\ No newline at end of file
+}
+
+
+
+#[cfg(all(test, unix))]
+mod tests{
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use bender_job::Task;
+    use blendfiles::Blendfile;
+    use blenders::BlenderInstall;
+    use transport::{MemoryQueue, TaskSource};
+
+    /// Return a fresh directory below the temp dir
+    fn scratch_dir() -> PathBuf{
+        let dir = env::temp_dir().join(format!("bender-worker-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write a stand-in for Blender that renders nothing and succeeds
+    fn fake_blender(dir: &Path) -> PathBuf{
+        let path = dir.join("blender");
+        fs::write(&path, "#!/bin/sh\nexit 0\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn update_renders_a_task_from_a_memory_queue(){
+        let dir = scratch_dir();
+        let mut config = WorkerConfig::new();
+        config.blendpath = dir.join("blend");
+        config.outpath = dir.join("frames");
+        config.disklimit = 0;
+        // Nobody listens there, status requests and uploads fail right away
+        config.bender_url = "http://127.0.0.1:9".to_string();
+        config.blenders = vec![BlenderInstall{ name: "fake".to_string(), path: fake_blender(&dir) }];
+        fs::create_dir_all(&config.blendpath).unwrap();
+        fs::create_dir_all(&config.outpath).unwrap();
+
+        let mut work = Work::new(config);
+        work.journal_path = dir.join("journal.json");
+
+        // The blendfile is optimized and checked already
+        let job_id = Uuid::new_v4().to_string();
+        let blendpath = work.config.blendpath.join("scene.blend");
+        fs::write(&blendpath, b"").unwrap();
+        let mut blendfile = Blendfile::new(blendpath);
+        blendfile.preflight_passed = true;
+        work.blendfiles.insert(job_id.clone(), Blend::Optimized(blendfile));
+        work.parent_jobs.insert(job_id.clone(), JobState::Running);
+
+        let mut task = Task::new_blender_single(1, "PNG".to_string());
+        task.parent_id = job_id.clone();
+        let task_id = task.id.clone();
+        let mut queue = MemoryQueue::new();
+        queue.push_task(&task).unwrap();
+
+        let finish = format!("finish.{}", work.config.id);
+        for _ in 0..20{
+            work.update(&mut queue);
+            if queue.events_with_prefix(&finish).next().is_some(){
+                break;
+            }
+        }
+
+        // The delivery has been acknowledged and the finished Task posted
+        assert_eq!(queue.queued_count(), 0);
+        assert_eq!(queue.unacked_count(), 0);
+        assert!(queue.nack(1, true).is_err());
+        let finished: Vec<Task> = queue.events_with_prefix(&finish)
+                                       .map(|(_, body)| Task::deserialize_from_u8(body).unwrap())
+                                       .collect();
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].id, task_id);
+        assert!(finished[0].is_finished());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,147 @@
+//! The work::blenders module picks the Blender executable a Task is rendered \
+//! with. The config can list several named installations, e.g.:
+//! ```toml
+//! [[blenders]]
+//! name = "2.79"
+//! path = "/opt/blender-2.79/blender"
+//!
+//! [[blenders]]
+//! name = "2.83 LTS"
+//! path = "/opt/blender-2.83/blender"
+//! ```
+//! The version a scene was made with is read from the `blender-version` field \
+//! of the Tasks data (e.g. `2.79` or `2.79b`). It matches the installation with \
+//! the same name, or else the first one with the same major and minor version. \
+//! Without a hint or a match the first installation is used, and without any \
+//! installations `blender` from PATH.
+//!
+//! The name and path of the executable that rendered a Task are stored as \
+//! `blender` and `blender-path` in its data. Once the Task is finished, a \
+//! `rendered-with` line sums them up for the `finish.<worker-id>` event.
+
+use ::*;
+use bender_job::Task;
+
+/// The field of the Tasks data that holds the version hint
+pub const VERSION_KEY: &str = "blender-version";
+
+
+
+
+/// A named Blender executable
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlenderInstall{
+    pub name: String,
+    pub path: PathBuf
+}
+
+
+impl Default for BlenderInstall {
+    fn default() -> Self {
+        Self::in_path()
+    }
+}
+
+
+impl BlenderInstall{
+    /// The `blender` found in PATH
+    pub fn in_path() -> Self{
+        BlenderInstall{
+            name: "default".to_string(),
+            path: PathBuf::from("blender")
+        }
+    }
+
+    /// Returns true if this installation matches the given version hint
+    fn matches(&self, hint: &str) -> bool{
+        normalize(&self.name) == normalize(hint)
+    }
+
+    /// Returns true if this installation has the same major and minor version \
+    /// as the given version hint
+    fn matches_minor(&self, hint: &str) -> bool{
+        match (major_minor(&self.name), major_minor(hint)){
+            (Some(a), Some(b)) => a == b,
+            _ => false
+        }
+    }
+}
+
+
+
+
+/// Return the installation for the given version hint (see module docs)
+pub fn select(installs: &[BlenderInstall], hint: Option<&str>) -> BlenderInstall{
+    let found = hint.and_then(|hint|{
+        installs.iter()
+                .find(|install| install.matches(hint))
+                .or_else(|| installs.iter().find(|install| install.matches_minor(hint)))
+    });
+    match found{
+        Some(install) => install.clone(),
+        None => installs.first().cloned().unwrap_or_default()
+    }
+}
+
+
+/// Return the configured installations, or `blender` from PATH if there are none
+pub fn installs(configured: &[BlenderInstall]) -> Vec<BlenderInstall>{
+    if configured.is_empty(){
+        vec![BlenderInstall::in_path()]
+    }else{
+        configured.to_vec()
+    }
+}
+
+
+/// Return the version hint of the given Task, if it has one
+pub fn version_hint(task: &Task) -> Option<&str>{
+    task.data.get(VERSION_KEY)
+             .map(|hint| hint.trim())
+             .filter(|hint| !hint.is_empty())
+}
+
+
+/// Lowercase the given version and drop a leading "blender"
+fn normalize(version: &str) -> String{
+    let version = version.trim().to_lowercase();
+    version.trim_start_matches("blender").trim().to_string()
+}
+
+
+/// Return major and minor of the given version (e.g. `(2, 79)` for `2.79b` or \
+/// `Blender 2.79 LTS`)
+fn major_minor(version: &str) -> Option<(u32, u32)>{
+    let normalized = normalize(version);
+    let version = normalized.split_whitespace().next()?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse::<u32>().ok()?;
+    let minor: String = parts.next()?.chars().take_while(|c| c.is_ascii_digit()).collect();
+    Some((major, minor.parse::<u32>().ok()?))
+}
+
+
+
+
+impl Work{
+    /// Return the installation the given Task should be rendered with
+    pub fn blender_for(&self, task: &Task) -> BlenderInstall{
+        let install = select(&self.config.blenders, version_hint(task));
+        if let Some(hint) = version_hint(task){
+            if !self.config.blenders.is_empty() && !install.matches(hint) && !install.matches_minor(hint){
+                notemsg(format!("[{}] No Blender {} configured, using Blender {}", &task.id[..6], hint, install.name));
+            }
+        }
+        install
+    }
+
+    /// Return the installation for the given job, using the version hint of \
+    /// the first of its Tasks that has one
+    pub fn blender_for_job(&self, id: &str) -> BlenderInstall{
+        let hint = self.tasks.iter()
+                             .filter(|t| t.parent_id == id)
+                             .filter_map(version_hint)
+                             .next();
+        select(&self.config.blenders, hint)
+    }
+}
@@ -9,16 +9,23 @@
 //! 3. Tasks that call the Blendfile their parent increment it and the last access\
 //!    time is stored
 //! 4. Once there is no unfinished Task left, `Work` runs another request to \
-//!    flaskbender asking about the Status of the Job. If the job is finished \
-//!    and a grace period has passed, the blendfile can be deleted.
+//!    flaskbender asking about the Status of the Job (see `work::jobstatus`). \
+//!    If the job has ended (finished, canceled or errored) and a grace period \
+//!    has passed, the blendfile is released. It stays in \
+//!    the cache (see `work::cache`) until it gets evicted.
 
 
 use ::*;
 use chrono::prelude::*;
 use chrono::Duration;
 use itertools::Itertools;
+use std::collections::HashMap;
 use bender_job::{Status, Task, Job, Command, FrameMap};
 use blend::Blend;
+use work::downloads::DownloadProgress;
+use work::jobstatus::JobState;
+use work::hooks::PipelineResult;
+use work::report::SceneReport;
 
 
 
@@ -41,52 +48,44 @@ impl Work{
 
     /// Update the parent Jobs status via request
     pub fn fetch_parent_jobs_stati(&mut self) {
-        // let Self{ tasks, last_staus, ..} = self;
         if self.last_status.should_run(){
-            // Clear the hashmap
-            self.parent_jobs.clear();
             // Collect all unique parent ids into a Vec
             let u: Vec<String> = self.unique_parent_ids()
                                      .map(|id| id.to_string())
                                      .collect();
             // For each unique parent id request the current job status from flaskbender
-            u.iter()
-             .for_each(|id|{
-                match self.request_jobstatus(id.to_string()) {
+            let mut stati = HashMap::new();
+            for id in u.iter(){
+                match self.request_jobstatus(id.as_str()) {
                     Ok(status) => {
-                        // Status is a string that looks like this: {'Job': 'Queued'}
-                        let status = status.split('\'').collect::<Vec<&str>>();
-                        match status.get(3){
-                            Some(s) => {
-                                self.last_status.set_last();
-                                self.parent_jobs.insert(id.to_string(), s.to_string());
-                            },
-                            None => {
-                                self.last_status.set_last_failed();
-                                errrun(format!("While requesting job status for [{}], malformed response", id.to_string()))
-                            }
-                        }
+                        self.last_status.set_last();
+                        stati.insert(id.to_string(), status);
                     },
                     Err(err) => {
                         self.last_status.set_last_failed();
-                        errrun(format!("While requesting job status for [{}]: {}", id.to_string(), err))
+                        errrun(format!("While requesting job status for [{}]: {}", id, err));
+                        // Keep what we knew before
+                        let state = self.parent_jobs.get(id).cloned().unwrap_or_default();
+                        self.parent_jobs.insert(id.to_string(), state);
                     }
                 }
-             })
+            }
+            self.parent_jobs.retain(|id, _| u.contains(id));
+            for (id, status) in stati{
+                self.set_job_status(id, status);
+            }
         }
     }
 
     /// Update the parent Jobs status via read
     pub fn read_parent_jobs_stati(&mut self) {
-        // Clear the hashmap
-        self.parent_jobs.clear();
         // Collect all unique parent ids into a Vec
         let u: Vec<String> = self.unique_parent_ids()
                                  .map(|id| id.to_string())
                                  .collect();
-        // For each unique parent id request the current job status from flaskbender
-        u.iter()
-         .for_each(|id|{
+        self.parent_jobs.retain(|id, _| u.contains(id));
+        // For each unique parent id read the current job status from its data.json
+        for id in u.iter(){
             let mut path = self.config.blendpath.clone();
             path.push(id);
             path.push("data.json");
@@ -94,40 +93,63 @@ impl Work{
             while !read {
                 read = match Job::from_datajson(&path){
                     Ok(job)  => {
-                        let s = format!("{}", job.status);
-                        self.parent_jobs.insert(id.to_string(), s);
+                        self.set_job_status(id.as_str(), job.status);
                         true
                     },
                     Err(ref e) if format!("{}", &e).contains("EOF") => {
-                        errrun(format!("EOF while reading job status for [{}]: {}\nTrying again", id.to_string(), e));
+                        errrun(format!("EOF while reading job status for [{}]: {}\nTrying again", id, e));
                         false
                     }
                     Err(err) => {
-                        errrun(format!("While reading job status for [{}]: {}", id.to_string(), err));
+                        errrun(format!("While reading job status for [{}]: {}", id, err));
                         true
                     }
                 }
             }
-        });
+        }
     }
 
-    /// Check whether the given job id is queued
-    pub fn job_is_finished<S>(&self, id: S) -> bool where S: Into<String> {
+    /// Store the status of the given job in its blendfile and in `parent_jobs`, \
+    /// reporting it if the job got paused, canceled or errored
+    pub fn set_job_status<S>(&mut self, id: S, status: Status) where S: Into<String>{
         let id = id.into();
-        match self.parent_jobs.get(&id){
-            Some(status) => {
-                status.clone().contains("Finished")
-            },
-            None => false
+        let state = JobState::from_status(&status);
+        let previous = self.parent_jobs.insert(id.clone(), state);
+        if previous.is_some() && previous != Some(state){
+            match state{
+                JobState::Paused   => notemsg(format!("Job [{}] has been paused", &id[..6])),
+                JobState::Canceled => errrun(format!("Job [{}] has been canceled", &id[..6])),
+                JobState::Errored  => errrun(format!("Job [{}] errored on the server", &id[..6])),
+                _ if previous == Some(JobState::Paused) => okrun(format!("Job [{}] has been resumed", &id[..6])),
+                _ => ()
+            }
+        }
+        if let Some(blendfile) = self.blendfiles.get_mut(&id).and_then(|blend| blend.blendfile_mut()){
+            blendfile.remote_job_status = Some(status);
         }
     }
 
+    /// Return the state of the given job (`JobState::Unknown` if there is none)
+    pub fn job_state<S>(&self, id: S) -> JobState where S: Into<String> {
+        self.parent_jobs.get(&id.into()).cloned().unwrap_or_default()
+    }
+
+    /// Check whether the given job is finished
+    pub fn job_is_finished<S>(&self, id: S) -> bool where S: Into<String> {
+        self.job_state(id).is_finished()
+    }
+
+    /// Check whether the given job is finished, canceled or errored
+    pub fn job_is_ended<S>(&self, id: S) -> bool where S: Into<String> {
+        self.job_state(id).is_ended()
+    }
+
     pub fn any_job_finished(&self) -> bool {
-        self.parent_jobs.iter().any(|(_id, status)|status.contains("Finished"))
+        self.parent_jobs.values().any(|state| state.is_finished())
     }
 
     pub fn all_jobs_finished(&self) -> bool {
-        self.parent_jobs.iter().all(|(_id, status)|status.contains("Finished"))
+        self.parent_jobs.values().all(|state| state.is_finished())
     }
 
     pub fn cleanup_frames(&self){
@@ -193,7 +215,7 @@ impl Work{
             let mut shall_finish: Vec<String> = potentially_finished.iter()
                                                    .cloned()
                                                    .filter(|id|{
-                                                        self.job_is_finished(id.as_str())
+                                                        self.job_is_ended(id.as_str())
                                                    })
                                                    .collect();
 
@@ -202,7 +224,7 @@ impl Work{
                 if !shall_finish.contains(&task.parent_id){
                     true
                 }else{
-                    println!("   [WORKER][{task_id}][{parent_id}][{short}] Removed Task because job was finished",
+                    println!("   [WORKER][{task_id}][{parent_id}][{short}] Removed Task because job has ended",
                         task_id=&task.id[..6],
                         parent_id=&task.parent_id[..6],
                         short=task.command.short());
@@ -227,50 +249,44 @@ impl Work{
                         })
                         .collect();
 
-            // Actually go and delete the blendfiles and erase them from self.blendfiles
-            shall_finish.iter()
-                        .map(|(id, path)|{
-                            let erase: bool = 
-                            if path.exists() {
-                                match fs::remove_file(&path){
-                                    Ok(_) => {
-                                        okrun(format!("Deleted blendfile for finished job [{}]", id));
-                                        let mut blend1 = path.clone();
-                                        blend1.set_extension("blend1");
-                                        match fs::remove_file(&blend1){
-                                            Ok(_) => (),
-                                            Err(_err) => ()
-                                        }
-                                        let mut framedirectory = path.clone();
-                                        framedirectory.pop();
-                                        framedirectory.pop();
-                                        framedirectory.push("frames");
-                                        framedirectory.push(id);
-                                        match fs::remove_dir(&framedirectory){
-                                            Ok(_) => true,
-                                            Err(err) => {
-                                                errrun(format!("Couldn't delete frame directory for finished job ({}): {}", framedirectory.to_string_lossy(), err));
-                                                false
-                                            }
-                                        }
-                                    },
-                                    Err(err) => {
-                                        errrun(format!("Couldn't delete blendfile for finished job ({}): {}", path.to_string_lossy(), err));
-                                        false
-                                    }
-                                }
-                                
-                            } else {
-                                 okrun(format!("ಠ_ಠ Tried to delete blendfile for finished job at {}, but it was already gone... that is okay I guess..", path.to_string_lossy()));
-                                 true
-                            };
-                            (id, erase)
-                        })
-                        .filter(|&(_, erase)| erase)
-                        .for_each(|(id, _)| {
-                            let _ = self.blendfiles.remove(id.as_str());
-                            // okrun(format!("Forgot blendfile for [{}]", id));
-                        } );
+            // Let go of the cached blendfiles, delete the frame directories and \
+            // erase the jobs from self.blendfiles. The files themselves stay in \
+            // the cache until they get evicted
+            for (id, path) in shall_finish.iter(){
+                self.cache.release(id);
+                okrun(format!("Released blendfile for finished job [{}] ({})", id, path.to_string_lossy()));
+                let framedirectory = self.frame_directory(id.as_str());
+                // The render logs are the only thing left in there
+                if let Err(err) = work::logs::remove_logs(&framedirectory){
+                    errrun(format!("Couldn't delete render logs for finished job ({}): {}", framedirectory.to_string_lossy(), err));
+                }
+                match fs::remove_dir(&framedirectory){
+                    Ok(_) => {
+                        let _ = self.blendfiles.remove(id.as_str());
+                        // okrun(format!("Forgot blendfile for [{}]", id));
+                    },
+                    Err(err) => errrun(format!("Couldn't delete frame directory for finished job ({}): {}", framedirectory.to_string_lossy(), err))
+                }
+            }
+        }
+    }
+
+    /// Return the directory the frames (and render logs) of the given job go to
+    pub fn frame_directory<S>(&self, id: S) -> PathBuf where S: Into<String>{
+        let mut framedirectory = self.config.outpath.clone();
+        framedirectory.push(id.into());
+        framedirectory
+    }
+
+    /// Delete the least recently used blendfiles no job needs anymore from the \
+    /// cache, if the free space on disk dropped below the disklimit. Jobs that \
+    /// have no blendfile here anymore let go of their cached file first
+    pub fn evict_blendfiles(&mut self){
+        let blendfiles = &self.blendfiles;
+        self.cache.release_unless(|job| blendfiles.contains_key(job));
+        let evicted = self.cache.evict(self.config.disklimit);
+        if evicted > 0{
+            self.add_history(format!("Worker [{}] evicted {} blendfile(s) from the cache", self.config.id, evicted));
         }
     }
 
@@ -278,10 +294,14 @@ impl Work{
     /// share a blendfile, only get it once.
     pub fn get_blendfiles(&mut self){
         if self.has_task() || !self.tasks.iter().all(|t| t.is_ended()){
+            // Take in what the download threads have to report
+            self.collect_downloads();
+
             // Get a unique list from the tasks job ids, ignoring job IDs that are 
-            // present as keys for the HashMap self.blendfiles already
+            // present as keys for the HashMap self.blendfiles already (or are \
+            // being downloaded right now)
             let ids: Vec<String> = self.unique_parent_ids()
-                                       .filter(|&id| !self.has_blendfile_by_id(id))
+                                       .filter(|&id| !self.has_blendfile_by_id(id) && !self.is_downloading_by_id(id))
                                        .map(|id| id.to_owned())
                                        .collect();
 
@@ -298,36 +318,34 @@ impl Work{
         }
     }
 
-    /// Deals with reqeusting new blendfiles from flaskbender, inserts the paths
-    /// into self.blendfiles
+    /// Deals with reqeusting new blendfiles from flaskbender. Blendfiles that \
+    /// are in the cache already are inserted into self.blendfiles right away, \
+    /// all others are handed to the download threads and marked as \
+    /// `Blend::Downloading` until `collect_downloads()` picks up the result
     pub fn fetch_blendfiles(&mut self, ids: Vec<String>){
         
         // Only dispatch a request if we have something to reqeust
         if !ids.is_empty(){ 
-            // For each remaining ID start a request and insert the resulting path
-            // into the hashmap
+            // For each remaining ID start a request
             if self.last_download.should_run(){
-                ids.iter()
-                    .for_each(|id|{
-                        match self.request_blendfile(id.to_owned()){
-                            Ok(path) => {
-                                // println!("{:?}", p);
-                                let opt_bf = if path.as_path().exists() { Blend::Downloaded(Blendfile::new(path)) } else { Blend::None };
-                                self.blendfiles.insert(id.to_string(), opt_bf);
-                                self.last_download.set_last();
-                            },
-                            Err(err) => {
-                                self.last_download.set_last_failed();
-                                errrun(format!("{}", err));
-                            }
-                        }
-                     });
+                for id in ids.iter(){
+                    // The Job might point at a cached file already
+                    let cached = self.cache.hash_for_job(id)
+                                           .cloned()
+                                           .and_then(|hash| self.cache.link(id, &hash).ok());
+                    if let Some(path) = cached{
+                        // The results of an earlier optimization are picked \
+                        // up from the cache by optimize_blendfiles()
+                        self.blendfiles.insert(id.to_string(), Blend::Downloaded(Blendfile::new(path)));
+                        continue;
+                    }
 
-                // If the length of unique ids equals the length of entries containing \
-                // Some<Blendfile> in Work::blendfiles, we assume that all files have \
-                // been downloaded 
-                if ids.len() == self.blendfiles.iter().map(|(_,x)| x).filter(|e|e.is_some()).count(){
-                    println!("{}", " ✔️ [WORKER] Downloaded all blendfiles".green());
+                    let request = self.blendfile_request(id.to_owned());
+                    if self.downloads.dispatch(request){
+                        self.blendfiles.insert(id.to_string(), Blend::Downloading{ progress: DownloadProgress::new() });
+                    }else{
+                        errrun(format!("Couldn't request blendfile for job [{}], the download threads are gone", &id[..6]));
+                    }
                 }
             }
         }
@@ -404,6 +422,15 @@ impl Work{
         }
     }
 
+    /// Returns true if the blendfile for the given job id is being downloaded
+    pub fn is_downloading_by_id<S>(&self, id: S) -> bool where S: Into<String>{
+        let id = id.into();
+        match self.blendfiles.get(&id) {
+            Some(entry) => entry.is_downloading(),
+            None => false
+        }
+    }
+
     /// Returns the path to the blendfile if it has one
     pub fn get_blendfile_for_task(&self, t: &Task) -> Option<PathBuf>{
         match self.blendfiles.get(&t.parent_id){
@@ -425,10 +452,12 @@ impl Work{
     }
 
 
-    // Get a iterator over references to unique parent IDs found in the tasks
+    // Get a iterator over references to unique parent IDs found in the tasks \
+    // (including the ones currently in a render slot)
     pub fn unique_parent_ids<'a>(&'a self) -> impl Iterator<Item = &str> + 'a{
         self.tasks
             .iter()
+            .chain(self.slots.iter().filter_map(|slot| slot.task.as_ref()))
             .map(|task| task.parent_id.as_str())
             .unique()
     }
@@ -448,7 +477,11 @@ pub struct Blendfile{
     pub lastaccess: DateTime<Utc>,
     pub frames_rendered: usize,
     pub remote_job_status: Option<Status>,
-    pub frame_durations: Vec<Duration>
+    pub frame_durations: Vec<Duration>,
+    /// What the hook pipeline found out while optimizing the file
+    pub hook_results: Option<PipelineResult>,
+    /// Whether the file passed the pre-flight validation
+    pub preflight_passed: bool
 }
 
 
@@ -464,18 +497,31 @@ impl Blendfile{
             lastaccess: now,
             frames_rendered: 0,
             remote_job_status: None,
-            frame_durations: Vec::<Duration>::new()
+            frame_durations: Vec::<Duration>::new(),
+            hook_results: None,
+            preflight_passed: false
         }
     }
 
+    /// Return the scene report written while the file was optimized
+    pub fn scene_report(&self) -> Option<&SceneReport>{
+        self.hook_results.as_ref().and_then(|results| results.report.as_ref())
+    }
+
     /// Run this function, once a frame has been rendered. This calculates the \
     /// duration between this call and the last access and pushes it to the Vec.
     /// Then the access time is updated and the frame count incremented
     pub fn increment_frame(&mut self) {
-        let now = Utc::now();
-        let duration = now - self.lastaccess;
+        let duration = Utc::now() - self.lastaccess;
+        self.add_frame(duration);
+    }
+
+    /// Run this function, once a frame has been rendered in a known duration \
+    /// (e.g. measured by the render slot). The duration is pushed to the Vec, \
+    /// the access time is updated and the frame count incremented
+    pub fn add_frame(&mut self, duration: Duration) {
         self.frame_durations.push(duration);
-        self.lastaccess = now;
+        self.lastaccess = Utc::now();
         self.frames_rendered += 1;
     }
 
@@ -0,0 +1,272 @@
+//! The work::cache module implements a content-addressed cache for blendfiles. \
+//! Files are stored as `<blendpath>/cache/<sha256>.blend`, and each Job that \
+//! uses one points at its entry. When artists resubmit the same scene as a new \
+//! Job, the known file is reused, including its optimized state.
+//!
+//! An index of all entries and of the Jobs pointing at them is kept at \
+//! `<blendpath>/cache/index.json`, so files still in use survive a restart of \
+//! the worker. Jobs the worker doesn't know about anymore (after a restart, a \
+//! hand back or a rejection) let go of their file before each eviction. \
+//! Entries no Job points at anymore stay around until the free disk space drops below \
+//! the `disklimit`, then the least recently used ones are evicted first.
+
+use ::*;
+use std::io;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use chrono::{Utc, DateTime};
+use work::hooks::PipelineResult;
+
+
+
+
+/// A single file in the cache
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheEntry{
+    pub size: u64,
+    pub optimized: bool,
+    /// The results of the hook pipeline that optimized the file
+    #[serde(default)]
+    pub hook_results: Option<PipelineResult>,
+    pub last_used: DateTime<Utc>
+}
+
+
+/// The contents of the index file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Index{
+    entries: BTreeMap<String, CacheEntry>,
+    /// Which Job points at which hash
+    #[serde(default)]
+    jobs: HashMap<String, String>
+}
+
+
+/// The content-addressed blendfile cache
+#[derive(Debug, Clone)]
+pub struct BlendCache{
+    dir: PathBuf,
+    entries: BTreeMap<String, CacheEntry>,
+    jobs: HashMap<String, String>
+}
+
+
+impl BlendCache{
+    /// Open the cache in the given blendpath, reading the index if there is \
+    /// one. Entries whose file went missing (and the Jobs pointing at them) \
+    /// are dropped
+    pub fn open<P>(blendpath: P) -> Self where P: Into<PathBuf>{
+        let mut dir = blendpath.into();
+        dir.push("cache");
+        let mut cache = BlendCache{
+            dir,
+            entries: BTreeMap::new(),
+            jobs: HashMap::new()
+        };
+        if let Ok(file) = File::open(cache.index_path()){
+            match serde_json::from_reader::<_, Index>(file){
+                Ok(index) => {
+                    cache.entries = index.entries;
+                    cache.jobs = index.jobs;
+                },
+                Err(err) => errrun(format!("Couldn't read the blendfile cache index, starting with an empty one: {}", err))
+            }
+        }
+        let missing: Vec<String> = cache.entries.keys()
+                                                .filter(|hash| !cache.path_for(hash).exists())
+                                                .cloned()
+                                                .collect();
+        for hash in missing{
+            cache.entries.remove(&hash);
+        }
+        let entries = &cache.entries;
+        cache.jobs.retain(|_, hash| entries.contains_key(hash));
+        cache
+    }
+
+    /// Return the path of the index file
+    fn index_path(&self) -> PathBuf{
+        self.dir.join("index.json")
+    }
+
+    /// Return the path of the cached file with the given hash
+    pub fn path_for(&self, hash: &str) -> PathBuf{
+        self.dir.join(format!("{}.blend", hash))
+    }
+
+    /// Return the hash of the file the given Job points at
+    pub fn hash_for_job(&self, job_id: &str) -> Option<&String>{
+        self.jobs.get(job_id)
+    }
+
+    /// Return the hashes of all files in the cache
+    pub fn hashes(&self) -> Vec<String>{
+        self.entries.keys().cloned().collect()
+    }
+
+    /// Returns true if there is a file with the given hash in the cache
+    pub fn contains(&self, hash: &str) -> bool{
+        self.entries.contains_key(hash)
+    }
+
+    /// Return the results of the hook pipeline that optimized the file the \
+    /// given Job points at
+    pub fn hook_results(&self, job_id: &str) -> Option<&PipelineResult>{
+        self.hash_for_job(job_id)
+            .and_then(|hash| self.entries.get(hash))
+            .filter(|entry| entry.optimized)
+            .and_then(|entry| entry.hook_results.as_ref())
+    }
+
+    /// Move the (verified) file at the given path into the cache, let the Job \
+    /// point at it and return its new path
+    pub fn insert<P>(&mut self, job_id: &str, hash: &str, path: P) -> io::Result<PathBuf> where P: AsRef<Path>{
+        fs::create_dir_all(&self.dir)?;
+        let target = self.path_for(hash);
+        if self.contains(hash) && target.exists(){
+            // Someone else was faster, keep the (maybe optimized) one we have
+            fs::remove_file(path.as_ref())?;
+        }else{
+            fs::rename(path.as_ref(), &target)?;
+            let size = fs::metadata(&target)?.len();
+            self.entries.insert(hash.to_string(), CacheEntry{ size, optimized: false, hook_results: None, last_used: Utc::now() });
+        }
+        self.link(job_id, hash)?;
+        Ok(target)
+    }
+
+    /// Let the Job point at the cached file with the given hash and return its \
+    /// path
+    pub fn link(&mut self, job_id: &str, hash: &str) -> io::Result<PathBuf>{
+        match self.entries.get_mut(hash){
+            Some(entry) => entry.last_used = Utc::now(),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("No cached blendfile with hash {}", hash)))
+        }
+        self.jobs.insert(job_id.to_string(), hash.to_string());
+        self.save()?;
+        Ok(self.path_for(hash))
+    }
+
+    /// Mark the file the given Job points at as optimized by the hook pipeline \
+    /// with the given results
+    pub fn set_optimized(&mut self, job_id: &str, results: PipelineResult) -> io::Result<()>{
+        let hash = match self.jobs.get(job_id){
+            Some(hash) => hash.clone(),
+            None => return Ok(())
+        };
+        if let Some(entry) = self.entries.get_mut(&hash){
+            entry.optimized = true;
+            entry.hook_results = Some(results);
+        }
+        self.save()
+    }
+
+    /// Let go of the file the given Job points at. The file stays in the cache \
+    /// until it gets evicted
+    pub fn release(&mut self, job_id: &str){
+        if let Some(hash) = self.jobs.remove(job_id){
+            if let Some(entry) = self.entries.get_mut(&hash){
+                entry.last_used = Utc::now();
+            }
+            if let Err(err) = self.save(){
+                errrun(format!("Couldn't write the blendfile cache index: {}", err));
+            }
+        }
+    }
+
+    /// Let go of the files of all Jobs the given closure returns false for, \
+    /// e.g. Jobs the worker has forgotten about. Returns the number of Jobs
+    pub fn release_unless<F>(&mut self, keep: F) -> usize where F: Fn(&str) -> bool{
+        let stale: Vec<String> = self.jobs.keys()
+                                          .filter(|job| !keep(job.as_str()))
+                                          .cloned()
+                                          .collect();
+        if stale.is_empty(){
+            return 0;
+        }
+        let now = Utc::now();
+        for job in stale.iter(){
+            if let Some(hash) = self.jobs.remove(job){
+                if let Some(entry) = self.entries.get_mut(&hash){
+                    entry.last_used = now;
+                }
+            }
+        }
+        if let Err(err) = self.save(){
+            errrun(format!("Couldn't write the blendfile cache index: {}", err));
+        }
+        stale.len()
+    }
+
+    /// Delete the least recently used files no Job points at, until there is \
+    /// more free space than the given limit (in GB) or nothing left to delete. \
+    /// Returns the number of evicted files
+    pub fn evict(&mut self, limit: u64) -> usize{
+        let mut evicted = 0;
+        if self.entries.is_empty(){
+            return evicted;
+        }
+        while !system::enough_space(&self.dir, limit){
+            let referenced: Vec<&String> = self.jobs.values().collect();
+            let oldest = self.entries.iter()
+                                     .filter(|(hash, _)| !referenced.contains(hash))
+                                     .min_by_key(|(_, entry)| entry.last_used)
+                                     .map(|(hash, _)| hash.clone());
+            let hash = match oldest{
+                Some(hash) => hash,
+                None => break
+            };
+            match self.remove(&hash){
+                Ok(_) => okrun(format!("Evicted cached blendfile {}", hash.get(..12).unwrap_or(&hash))),
+                Err(err) => {
+                    errrun(format!("Couldn't evict cached blendfile at {}: {}", self.path_for(&hash).to_string_lossy(), err));
+                    break;
+                }
+            }
+            evicted += 1;
+        }
+        if evicted > 0{
+            if let Err(err) = self.save(){
+                errrun(format!("Couldn't write the blendfile cache index: {}", err));
+            }
+        }
+        evicted
+    }
+
+    /// Let go of the file the given Job points at and delete it right away, \
+    /// unless another Job points at it as well
+    pub fn discard(&mut self, job_id: &str) -> io::Result<()>{
+        let hash = match self.jobs.remove(job_id){
+            Some(hash) => hash,
+            None => return Ok(())
+        };
+        if !self.jobs.values().any(|h| h == &hash){
+            self.remove(&hash)?;
+        }
+        self.save()
+    }
+
+    /// Delete the file with the given hash from the cache
+    fn remove(&mut self, hash: &str) -> io::Result<()>{
+        let path = self.path_for(hash);
+        match fs::remove_file(&path){
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err)
+        }
+        // Blender leaves a backup behind when saving the optimized file
+        let _ = fs::remove_file(path.with_extension("blend1"));
+        self.entries.remove(hash);
+        Ok(())
+    }
+
+    /// Write the index to disk
+    fn save(&self) -> io::Result<()>{
+        fs::create_dir_all(&self.dir)?;
+        let index = Index{ entries: self.entries.clone(), jobs: self.jobs.clone() };
+        let json = serde_json::to_vec_pretty(&index)
+                       .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        fs::write(self.index_path(), json)
+    }
+}
+
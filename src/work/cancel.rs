@@ -0,0 +1,115 @@
+//! The work::cancel module drops jobs that have been canceled in flaskbender. \
+//! The cancellation is noticed via the polled job status (see \
+//! `work::jobstatus`), which includes the jobs of Tasks that are rendering. \
+//! For every canceled job:
+//! 1. Its running renders are killed
+//! 2. All of its Tasks are removed from the worker. Deliveries that haven't \
+//!    been acknowledged yet are acknowledged now, so the broker drops them, and \
+//!    a `cancel.<worker-id>` event is posted for each Task
+//! 3. Its blendfile is deleted from the cache (unless another job uses the \
+//!    same file) and its render logs are deleted. In independent mode its \
+//!    frames in `<outpath>/<job-id>` are deleted as well
+
+use ::*;
+use bender_job::Task;
+use work::transport::Transport;
+use work::uploads::renderpaths;
+
+
+
+
+impl Work{
+    /// Drop all jobs whose status says they have been canceled
+    pub fn cancel_jobs<T>(&mut self, transport: &mut T) where T: Transport{
+        let canceled: Vec<String> = self.parent_jobs.iter()
+                                                    .filter(|(_, state)| state.is_canceled())
+                                                    .map(|(id, _)| id.clone())
+                                                    .collect();
+        for id in canceled.iter(){
+            self.cancel_job(id.as_str(), transport);
+        }
+    }
+
+
+    /// Kill the renders of the given job, drop its Tasks and delete its files
+    pub fn cancel_job<T>(&mut self, id: &str, transport: &mut T) where T: Transport{
+        let mut dropped = Vec::new();
+
+        // Kill the running renders first
+        for slot in self.slots.iter_mut().filter(|slot| slot.task.as_ref().map(|t| t.parent_id == id).unwrap_or(false)){
+            if let Some(ref mut render) = slot.render{
+                if let Err(err) = render.kill(){
+                    errrun(format!("Couldn't kill render (pid {}): {}", render.id(), err));
+                }
+            }
+            if let Some(t) = slot.clear(){
+                dropped.push(t);
+            }
+        }
+
+        let (of_job, rest): (Vec<Task>, Vec<Task>) = self.tasks.drain(..)
+                                                             .partition(|t| t.parent_id == id);
+        self.tasks = rest;
+        dropped.extend(of_job);
+
+        for t in dropped.iter(){
+            drop_task(t, self.config.id, transport);
+        }
+
+        // Delete the files right away instead of waiting for a grace period. \
+        // In server mode the frames are rendered in place, so only the \
+        // render logs are ours to delete
+        let framedirectory = self.frame_directory(id);
+        if self.config.mode.is_independent(){
+            for path in dropped.iter().flat_map(renderpaths){
+                if path.is_file(){
+                    if let Err(err) = fs::remove_file(&path){
+                        errrun(format!("Couldn't delete frame of canceled job at {}: {}", path.to_string_lossy(), err));
+                    }
+                }
+            }
+            if framedirectory.exists(){
+                if let Err(err) = fs::remove_dir_all(&framedirectory){
+                    errrun(format!("Couldn't delete frame directory of canceled job ({}): {}", framedirectory.to_string_lossy(), err));
+                }
+            }
+        }else if framedirectory.exists(){
+            if let Err(err) = work::logs::remove_logs(&framedirectory){
+                errrun(format!("Couldn't delete render logs of canceled job ({}): {}", framedirectory.to_string_lossy(), err));
+            }
+        }
+        if let Err(err) = self.cache.discard(id){
+            errrun(format!("Couldn't delete blendfile of canceled job [{}]: {}", &id[..6], err));
+        }
+        // A download that is still running is thrown away once it is done
+        self.blendfiles.remove(id);
+        self.parent_jobs.remove(id);
+
+        okrun(format!("Dropped canceled job [{}] ({} Task(s))", &id[..6], dropped.len()));
+        let h = format!("Worker [{}] dropped canceled job [{}] with {} Task(s)", self.config.id, id, dropped.len());
+        self.add_history(h.as_str());
+    }
+}
+
+
+/// Acknowledge the delivery of a Task of a canceled job (unless that already \
+/// happened when it finished or errored) and tell everyone it has been dropped
+fn drop_task<T>(task: &Task, worker_id: Uuid, transport: &mut T) where T: Transport{
+    if !task.is_ended(){
+        if let Some(tag) = task.data.get("task-delivery-tag").and_then(|tag| tag.parse::<u64>().ok()){
+            if let Err(err) = transport.ack(tag){
+                errrun(format!("[{}] Couldn't acknowledge Task of canceled job: {}", &task.id[..6], err));
+            }
+        }
+    }
+    println!(" ✖ [WORKER][{task_id}][{parent_id}][{short}] Dropped Task, its job has been canceled",
+        task_id=&task.id[..6],
+        parent_id=&task.parent_id[..6],
+        short=task.command.short());
+    let routing_key = format!("cancel.{}", worker_id);
+    match task.serialize_to_u8(){
+        Ok(task_json) => transport.post_event(routing_key, task_json),
+        Err(err) => eprintln!(" ✖ [WORKER][{}] Error: Failed to deserialize Task: {}", &task.id[..6], err)
+    }
+}
+
@@ -0,0 +1,124 @@
+//! The work::capabilities module describes what a worker is able to render: \
+//! the Blender versions (see `work::blenders`), the number of CPUs, the memory, the enabled add-ons and \
+//! the `tags` from the config (e.g. `gpu`). The record is published as \
+//! `capabilities.<worker-id>` on startup.
+//!
+//! Besides the shared `work` queue the worker pulls Tasks from queues named \
+//! after its capabilities, so jobs can target the workers that can run them:
+//! - `work.blender-<major.minor>` for every installed version (e.g. \
+//!   `work.blender-2.80`)
+//! - `work.tag-<tag>` for every tag (e.g. `work.tag-gpu`)
+//!
+//! These are checked before the shared queue.
+
+use ::*;
+use config::WorkerConfig;
+use work::transport::EventSink;
+use work::blenders;
+
+/// The queue every worker takes Tasks from
+pub const SHARED_QUEUE: &str = "work";
+
+
+
+
+/// What this worker is able to render
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Capabilities{
+    pub worker_id: Uuid,
+    /// The version of the default Blender (the first installation)
+    pub blender_version: Option<String>,
+    /// The versions of all installations
+    pub blender_versions: Vec<String>,
+    pub cpus: usize,
+    pub memory_bytes: Option<u64>,
+    pub render_slots: usize,
+    pub addons: Vec<String>,
+    pub tags: Vec<String>,
+    /// The queues the worker takes Tasks from, in the order they are checked
+    pub queues: Vec<String>
+}
+
+
+impl Capabilities{
+    /// Detect the capabilities of this machine. This starts Blender to list \
+    /// the add-ons, so it takes a moment
+    pub fn detect(config: &WorkerConfig) -> Self{
+        let installs = blenders::installs(&config.blenders);
+        let blender_versions: Vec<String> = installs.iter()
+                                                    .filter_map(|install| system::blender_version(&install.path))
+                                                    .collect();
+        let blender_version = blender_versions.first().cloned();
+        let tags: Vec<String> = config.tags.iter()
+                                           .map(|tag| sanitize(tag))
+                                           .filter(|tag| !tag.is_empty())
+                                           .collect();
+        let queues = queue_names(&blender_versions, &tags);
+        Capabilities{
+            worker_id: config.id,
+            blender_version,
+            blender_versions,
+            cpus: system::cpu_count(),
+            memory_bytes: system::memory_bytes(),
+            render_slots: config.render_slots,
+            addons: system::blender_addons(&installs[0].path),
+            tags,
+            queues
+        }
+    }
+
+    /// Post the record as `capabilities.<worker-id>`
+    pub fn publish<E>(&self, events: &mut E) where E: EventSink{
+        match serde_json::to_vec(self){
+            Ok(json) => events.post_event(format!("capabilities.{}", self.worker_id), json),
+            Err(err) => errrun(format!("Couldn't serialize the capabilities: {}", err))
+        }
+    }
+
+    /// Print the record for the startup screen
+    pub fn print(&self){
+        scrnmsg(format!("Blender version:                    {}", self.blender_version.as_deref().unwrap_or("unknown")));
+        if self.blender_versions.len() > 1{
+            scrnmsg(format!("Installed Blender versions:         {}", self.blender_versions.join(", ")));
+        }
+        let memory = self.memory_bytes.map(|bytes| format!("{:.1} GB", bytes as f64 / 1e9)).unwrap_or_else(|| "unknown".to_string());
+        scrnmsg(format!("CPUs / Memory:                      {} / {}", self.cpus, memory));
+        if !self.tags.is_empty(){
+            scrnmsg(format!("Tags:                               {}", self.tags.join(", ")));
+        }
+        scrnmsg(format!("Taking Tasks from the queues:       {}", self.queues.join(", ")));
+    }
+}
+
+
+
+
+/// Return the queues for the given Blender versions and tags, the shared \
+/// queue last
+pub fn queue_names(blender_versions: &[String], tags: &[String]) -> Vec<String>{
+    let mut queues = Vec::new();
+    for version in blender_versions.iter(){
+        // Only major and minor, patch releases render the same
+        let version: Vec<&str> = version.split('.').take(2).collect();
+        let queue = format!("{}.blender-{}", SHARED_QUEUE, sanitize(&version.join(".")));
+        if !queues.contains(&queue){
+            queues.push(queue);
+        }
+    }
+    for tag in tags.iter(){
+        queues.push(format!("{}.tag-{}", SHARED_QUEUE, tag));
+    }
+    queues.push(SHARED_QUEUE.to_string());
+    queues
+}
+
+
+/// Lowercase the given name and drop everything that doesn't belong in a \
+/// queue name
+fn sanitize(name: &str) -> String{
+    name.trim()
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_' || *c == '.')
+        .collect()
+}
@@ -0,0 +1,160 @@
+//! The work::chunked module implements a resumable upload protocol for large \
+//! frames (e.g. multilayer EXRs). A frame is sent in chunks of a configurable \
+//! size. When the link breaks, only the chunks the server hasn't received yet \
+//! are sent again on the next try.
+//!
+//! ## Endpoint contract
+//! All requests go to `<bender_url>/job/<job-id>/<task-id>/chunked/<filename>` \
+//! and the offsets/lengths are given in bytes:
+//!
+//! | Request | Headers sent | Response |
+//! |---------|--------------|----------|
+//! | `HEAD`  | – | `200` with `Upload-Offset` (bytes received so far), `404` if nothing was received yet |
+//! | `PATCH` | `Upload-Offset`, `Upload-Length` (of the whole file), body: the chunk as `application/offset+octet-stream` | `204` with the new `Upload-Offset`, `409` with the actual `Upload-Offset` if the offset didn't match |
+//! | `POST`  | `Upload-Length`, `Upload-SHA256` (hex) | `201` once the file is complete and the hash matches, `422` if it doesn't (the received data is discarded) |
+//!
+//! A server without the endpoint answers `405` to the `HEAD` or `404`/`405` \
+//! to the first `PATCH`, which is reported as `Unsupported`, so the caller can \
+//! fall back to a single request.
+//!
+//! A reference implementation of the receiving side lives in `work::standin`.
+
+use ::*;
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+use std::fs::File;
+use reqwest::{Method, StatusCode};
+use reqwest::header::CONTENT_TYPE;
+use config::GenResult;
+use work::client::BenderClient;
+use work::requests::sha256_hex;
+
+/// Number of bytes the server received so far (or the offset of a chunk)
+pub const UPLOAD_OFFSET: &str = "Upload-Offset";
+/// Size of the whole file in bytes
+pub const UPLOAD_LENGTH: &str = "Upload-Length";
+/// Hex encoded SHA-256 hash of the whole file
+pub const UPLOAD_SHA256: &str = "Upload-SHA256";
+
+
+
+
+/// The server doesn't know the chunked endpoint
+#[derive(Debug)]
+pub struct Unsupported{
+    pub status: StatusCode
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Server has no chunked upload endpoint ({})", self.status)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+
+/// Returns true if the status says there is no such endpoint
+fn is_unsupported(status: StatusCode) -> bool{
+    status == StatusCode::NOT_FOUND || status == StatusCode::METHOD_NOT_ALLOWED
+}
+
+
+
+
+/// Return the URL chunks of the file at the given path are sent to
+pub fn chunked_url(base_url: &str, path: &Path) -> GenResult<String>{
+    match path.file_name(){
+        Some(name) => Ok(format!("{}/chunked/{}", base_url, name.to_string_lossy())),
+        None => Err(From::from(format!("{} has no file name", path.to_string_lossy())))
+    }
+}
+
+
+/// Upload the file at the given path in chunks of `chunk_bytes`, resuming \
+/// from wherever the server says the last try stopped
+pub fn upload_chunked<P>(client: &BenderClient, base_url: &str, path: P, chunk_bytes: u64) -> GenResult<()> where P: AsRef<Path>{
+    let path = path.as_ref();
+    let url = chunked_url(base_url, path)?;
+    let total = fs::metadata(path)?.len();
+    let mut file = File::open(path)?;
+
+    let mut offset = query_offset(client, &url)?;
+    // Nothing has been received if the endpoint is missing, so a 404 to the
+    // first chunk means the same
+    let mut first = true;
+    if offset > total{
+        return Err(From::from(format!("Server claims to have received {} bytes of a {} byte file", offset, total)));
+    }
+
+    while offset < total{
+        let length = std::cmp::min(chunk_bytes.max(1), total - offset);
+        let mut chunk = vec![0u8; length as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut chunk)?;
+
+        let response = client.request_with_body(Method::PATCH, url.as_str(), chunk)
+                             .header(CONTENT_TYPE, "application/offset+octet-stream")
+                             .header(UPLOAD_OFFSET, offset.to_string())
+                             .header(UPLOAD_LENGTH, total.to_string())
+                             .send()?;
+
+        let status = response.status();
+        if first && is_unsupported(status){
+            return Err(Box::new(Unsupported{ status }));
+        }
+        first = false;
+        offset = if status.is_success(){
+            header_offset(&response).unwrap_or(offset + length)
+        }else if status == StatusCode::CONFLICT{
+            // We are out of sync, continue where the server is
+            match header_offset(&response){
+                Some(actual) if actual <= total => actual,
+                _ => return Err(From::from("Server responded with a conflict, but without a valid offset"))
+            }
+        }else{
+            return Err(From::from(format!("Server responded with {} to the chunk at offset {}", status, offset)));
+        };
+    }
+
+    // Let the server check the whole file
+    let mut response = client.post(url.as_str())
+                             .header(UPLOAD_LENGTH, total.to_string())
+                             .header(UPLOAD_SHA256, sha256_hex(path)?)
+                             .send()?;
+    let status = response.status();
+    if status.is_success(){
+        Ok(())
+    }else if status == StatusCode::UNPROCESSABLE_ENTITY{
+        Err(From::from("Server discarded the upload, because the hash didn't match"))
+    }else{
+        let text = response.text().unwrap_or_else(|_| "Couldn't descramble response".to_string());
+        Err(From::from(format!("Server responded with {} when finishing the upload: {}", status, text.trim())))
+    }
+}
+
+
+/// Ask the server how many bytes of the file it received already
+fn query_offset(client: &BenderClient, url: &str) -> GenResult<u64>{
+    let response = client.head(url)
+                         .send()?;
+    let status = response.status();
+    if status == StatusCode::NOT_FOUND{
+        Ok(0)
+    }else if status == StatusCode::METHOD_NOT_ALLOWED{
+        Err(Box::new(Unsupported{ status }))
+    }else if status.is_success(){
+        Ok(header_offset(&response).unwrap_or(0))
+    }else{
+        Err(From::from(format!("Server responded with {} when asked for the upload offset", status)))
+    }
+}
+
+
+/// Return the value of the Upload-Offset header
+fn header_offset(response: &reqwest::Response) -> Option<u64>{
+    response.headers()
+            .get(UPLOAD_OFFSET)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+}
@@ -0,0 +1,245 @@
+//! The work::client module holds the HTTP client every request to flaskbender \
+//! goes through (blendfiles, job status, frame uploads). Besides the \
+//! `User-Agent` it identifies the worker with its `id` and authenticates it \
+//! with the secret from the `auth` table of the config, so the server can \
+//! reject unknown workers:
+//! - `Token`: `Authorization: Bearer <secret>`
+//! - `Hmac`: `X-Bender-Timestamp` (unix seconds), `X-Bender-Nonce` (a random \
+//!   uuid) and `X-Bender-Signature`, the hex encoded HMAC-SHA256 of
+//!   ```text
+//!   <METHOD>\n<path?query>\n<timestamp>\n<nonce>\n<worker-id>\n<body-sha256>
+//!   ```
+//!   keyed with the secret. `<body-sha256>` is the hex encoded SHA-256 of the \
+//!   body (of the empty string for requests without one, of the frame for \
+//!   multipart uploads). The secret never leaves the worker and the server \
+//!   should reject timestamps older than a few minutes and nonces it has seen \
+//!   before
+//! - `None`: only `X-Bender-Worker` is sent, for servers without authentication
+//!
+//! Every request carries `X-Bender-Worker: <worker-id>`.
+
+use ::*;
+use std::fmt;
+use chrono::Utc;
+use config::WorkerConfig;
+use reqwest::{Method, RequestBuilder};
+use reqwest::header::{AUTHORIZATION, USER_AGENT};
+use sha2::{Sha256, Digest};
+use work::requests::{hmac_sha256, to_hex};
+
+/// Header carrying the id of the worker
+pub const WORKER_HEADER: &str = "X-Bender-Worker";
+/// Header carrying the time a HMAC signed request was made (unix seconds)
+pub const TIMESTAMP_HEADER: &str = "X-Bender-Timestamp";
+/// Header carrying the random nonce of a HMAC signed request
+pub const NONCE_HEADER: &str = "X-Bender-Nonce";
+/// Header carrying the HMAC signature
+pub const SIGNATURE_HEADER: &str = "X-Bender-Signature";
+/// The hex encoded SHA-256 of an empty body
+pub const EMPTY_BODY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+
+
+
+/// How the worker authenticates itself
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AuthMode{
+    None,
+    Token,
+    Hmac
+}
+
+impl Default for AuthMode {
+    fn default() -> Self {
+        AuthMode::Token
+    }
+}
+
+
+/// The `auth` table of the `WorkerConfig`. The secret is either the API token \
+/// or the HMAC key, depending on the mode
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct WorkerAuth{
+    pub mode: AuthMode,
+    #[serde(default)]
+    pub secret: String
+}
+
+impl fmt::Debug for WorkerAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keep the secret out of logs
+        write!(f, "WorkerAuth {{ mode: {:?}, secret: \"***\" }}", self.mode)
+    }
+}
+
+impl WorkerAuth{
+    /// Create a new WorkerAuth with a random token
+    pub fn new() -> Self{
+        WorkerAuth{
+            mode: AuthMode::Token,
+            secret: generate_secret()
+        }
+    }
+
+    /// Read the secret from the `BENDER_WORKER_SECRET` environment variable \
+    /// (e.g. set in the systemd unit). Without it no authentication is used
+    pub fn from_env() -> Self{
+        match env::var("BENDER_WORKER_SECRET"){
+            Ok(ref secret) if !secret.trim().is_empty() => WorkerAuth{
+                mode: AuthMode::Token,
+                secret: secret.trim().to_string()
+            },
+            _ => WorkerAuth{
+                mode: AuthMode::None,
+                secret: String::new()
+            }
+        }
+    }
+
+    /// Returns true if the mode needs a secret, but there is none
+    pub fn is_missing_secret(&self) -> bool{
+        self.mode != AuthMode::None && self.secret.is_empty()
+    }
+}
+
+
+/// Return a new random secret (64 hex characters)
+pub fn generate_secret() -> String{
+    format!("{}{}", Uuid::new_v4().to_simple(), Uuid::new_v4().to_simple())
+}
+
+
+
+
+/// A reqwest Client that adds the identification and authentication headers \
+/// of this worker to every request
+#[derive(Debug, Clone)]
+pub struct BenderClient{
+    client: reqwest::Client,
+    worker_id: Uuid,
+    auth: WorkerAuth
+}
+
+
+impl BenderClient{
+    /// Create a new BenderClient for the worker with the given config
+    pub fn new(config: &WorkerConfig) -> Self{
+        BenderClient{
+            client: reqwest::Client::new(),
+            worker_id: config.id,
+            auth: config.auth.clone()
+        }
+    }
+
+    /// Start a request without a body with the given method to the given URL
+    pub fn request(&self, method: Method, url: &str) -> RequestBuilder{
+        self.request_with_body_hash(method, url, EMPTY_BODY_SHA256)
+    }
+
+    /// Start a request with the given method to the given URL and set its \
+    /// body, so it is signed along with it
+    pub fn request_with_body<B>(&self, method: Method, url: &str, body: B) -> RequestBuilder where B: Into<Vec<u8>>{
+        let body = body.into();
+        let body_hash = to_hex(&Sha256::digest(&body));
+        self.request_with_body_hash(method, url, &body_hash)
+            .body(body)
+    }
+
+    /// Start a request with the given method to the given URL whose body \
+    /// has the given hex encoded SHA-256 hash. The caller sets the body, e.g. \
+    /// a streamed one that can't be hashed here
+    pub fn request_with_body_hash(&self, method: Method, url: &str, body_hash: &str) -> RequestBuilder{
+        let builder = self.client.request(method.clone(), url)
+                                 .header(USER_AGENT, "bender-worker")
+                                 .header(WORKER_HEADER, self.worker_id.to_string());
+        match self.auth.mode{
+            AuthMode::None => builder,
+            AuthMode::Token => builder.header(AUTHORIZATION, format!("Bearer {}", self.auth.secret)),
+            AuthMode::Hmac => {
+                let timestamp = Utc::now().timestamp().to_string();
+                let nonce = Uuid::new_v4().to_string();
+                let signature = sign_request(&self.auth.secret, method.as_str(), &path_and_query(url), &timestamp, &nonce, &self.worker_id.to_string(), body_hash);
+                builder.header(TIMESTAMP_HEADER, timestamp)
+                       .header(NONCE_HEADER, nonce)
+                       .header(SIGNATURE_HEADER, signature)
+            }
+        }
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder{
+        self.request(Method::GET, url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder{
+        self.request(Method::POST, url)
+    }
+
+    pub fn patch(&self, url: &str) -> RequestBuilder{
+        self.request(Method::PATCH, url)
+    }
+
+    pub fn head(&self, url: &str) -> RequestBuilder{
+        self.request(Method::HEAD, url)
+    }
+}
+
+
+/// Return the HMAC signature for a request, see the module documentation
+pub fn sign_request(secret: &str, method: &str, path: &str, timestamp: &str, nonce: &str, worker_id: &str, body_hash: &str) -> String{
+    let message = format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, timestamp, nonce, worker_id, body_hash);
+    to_hex(&hmac_sha256(secret.as_bytes(), message.as_bytes()))
+}
+
+
+/// Return the path and query of a URL as it is sent to the server, e.g. \
+/// `/job/worker/status/<id>`
+fn path_and_query(url: &str) -> String{
+    match reqwest::Url::parse(url){
+        Ok(url) => match url.query(){
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string()
+        },
+        Err(_) => url.to_string()
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn sign_request_signs_method_path_timestamp_nonce_worker_and_body(){
+        let sign = |method: &str, nonce: &str, body_hash: &str| sign_request("correct horse battery staple",
+                                                                             method,
+                                                                             "/job/worker/status/3a6d8e6f?part=1",
+                                                                             "1369353600",
+                                                                             nonce,
+                                                                             "6f1c6b0e-6c2a-4b8e-9d4a-1f0a2b3c4d5e",
+                                                                             body_hash);
+        let nonce = "0b8e4f2c-2f3a-4d6e-9a1b-7c5d3e2f1a0b";
+        let signature = sign("GET", nonce, EMPTY_BODY_SHA256);
+        assert_eq!(signature, "6b56691b7a790c1c1f58f8c7a8e24a3ac605e0ebee2b846e78a14ed6d6dcb9ca");
+        assert_eq!(sign("POST", nonce, EMPTY_BODY_SHA256), "265f631266ce2090ed2ed3bcd3980b34aa9b827ad9d0b27b5ffd5928db5cd904");
+
+        let body_hash = to_hex(&Sha256::digest(br#"{"request":"blendfile"}"#));
+        assert_eq!(body_hash, "90ed6ad22b7d027e0711bc111aa812d6f826b831e17138f547613c9cdf9fe11c");
+        assert_eq!(sign("GET", nonce, &body_hash), "19ee104ca0d3e3a046290d9efdbe6b32b1b752c763fe296cc926cc184d21c723");
+
+        // Any change to the message changes the signature
+        assert_ne!(signature, sign("GET", "1b8e4f2c-2f3a-4d6e-9a1b-7c5d3e2f1a0b", EMPTY_BODY_SHA256));
+    }
+
+    #[test]
+    fn empty_body_hash_is_the_sha256_of_nothing(){
+        assert_eq!(to_hex(&Sha256::digest(b"")), EMPTY_BODY_SHA256);
+    }
+
+    #[test]
+    fn path_and_query_leaves_out_scheme_and_host(){
+        assert_eq!(path_and_query("http://localhost:5000/job/worker/status/3a6d8e6f"), "/job/worker/status/3a6d8e6f");
+        assert_eq!(path_and_query("http://localhost:5000/job/worker/status/3a6d8e6f?part=1"), "/job/worker/status/3a6d8e6f?part=1");
+    }
+}
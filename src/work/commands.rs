@@ -2,12 +2,18 @@
 //! spawning and processing of actual Commands (as defind in `bender_job::Command`)
 
 use ::*;
-use std::thread::sleep;
 use std::process::{Stdio};
-use std::io::{BufRead, BufReader};
 use std::process::Command;
-use std::time::Duration;
 use std::fs::DirBuilder;
+use work::transport::Transport;
+use work::slots::Slot;
+use work::render::{Render, Output};
+use work::progress::Progress;
+use work::logs::{self, RenderLog};
+use chrono::{Utc, DateTime};
+use work::retry::Failure;
+use work::blenders::BlenderInstall;
+use bender_job::Task;
 
 #[cfg(unix)]
 use std::os::unix::fs::DirBuilderExt;
@@ -78,110 +84,182 @@ impl Work{
     }
 
 
-    /// Spawn constructed commands and either error or finish the current Task. \
-    /// This method is meant to run in the update loop, that means it behaves \
-    /// differently depending on wheter there is a current task or command or not.
+    /// Spawn constructed commands and either error or finish the Tasks in the \
+    /// render slots. This method is meant to run in the update loop, that means \
+    /// it behaves differently for each slot depending on wheter there is a \
+    /// task or command in it or not.
     //
-    pub fn run_command(&mut self, channel: &mut Channel){
-        let exitstatus = match self{
-            // When there is no command but a current task, create a command and spawn it
-            Work{command: None, current: Some(task), ..} => {
+    pub fn run_command<T>(&mut self, transport: &mut T) where T: Transport{
+        for i in 0..self.slots.len(){
+            let exitstatus = self.run_slot(i);
+
+            // println!("Debug: We have a exitstatus of: {:?}", exitstatus);
+
+            match exitstatus{
+                ExitStatus::None => (),
+                ExitStatus::Running => {
+                    match self.slots[i].task{
+                        Some(ref mut c) if !c.is_running() => {
+                            c.start();
+                            // println!("Debug: Started Task because it wasn't running");
+                        },
+                        _ => ()
+                    }
+                    self.post_progress(i, transport);
+                },
+                ExitStatus::Errored(failure, err) => self.error_current(i, failure, err, transport),
+                ExitStatus::Finished => self.finish_current(i, transport)
+            }
+        }
+    }
+
+
+    /// Spawn the command for the Task in the slot with the given index or check \
+    /// on the command if it is already running. This never blocks, the output \
+    /// of the command is collected in the background by its `Render`
+    fn run_slot(&mut self, i: usize) -> ExitStatus{
+        let is_server = self.config.mode.is_server();
+        let outpath = self.config.outpath.clone();
+        let (log_max_bytes, log_rotations) = (self.config.log_max_bytes, self.config.log_rotations);
+        let timeout = self.config.render_timeout_minutes;
+        let stall_timeout = self.config.stall_timeout_minutes;
+        // Pick the Blender before the slot is borrowed, only needed for new renders
+        let blender = match self.slots[i]{
+            Slot{render: None, task: Some(ref task), ..} => self.blender_for(task),
+            _ => BlenderInstall::in_path()
+        };
+        let slot = &mut self.slots[i];
+        match *slot{
+            // When there is no command but a task, create a command and spawn it
+            Slot{render: None, task: Some(ref mut task), ..} => {
                 // If there is no command create one
                 if task.command.is_blender(){
-                    // Replace only first "blender " in command string
-                    let s = task.command.to_string().unwrap().replacen("blender ", "", 1);
-                    match shlex::split(&s){
-                        Some(args) => {
-                            // If we are in server mode assume we run linux and set the output spawn
-                            // with gid "bender"
-                            if self.config.mode.is_server() && cfg!(unix){
-                                use std::os::unix::process::CommandExt;
-                                // Get bender gid when we are on a server
-                                let mut cache = UsersCache::new();
-                                let group = cache.get_group_by_name("bender").expect("There is no group called 'bender' please create the requeired users and groups!");
-
-                                match Command::new("blender")
-                                                       .args(args.clone())
-                                                       .gid(group.gid())
-                                                       .stdout(Stdio::piped())
-                                                       .stderr(Stdio::piped())
-                                                       .spawn(){
-                                    Ok(c) => {
-                                        println!("{}", format!(" ⚟ [WORKER][{task_id}][{parent_id}][{short}] Dispatched Command", 
-                                            task_id=&task.id[..6], 
-                                            parent_id=&task.parent_id[..6],
-                                            short=task.command.short()).yellow());
-                                        self.command = Some(c);
-                                        
-                                        ExitStatus::Running
-                                    },
-                                    Err(err) => ExitStatus::Errored(
-                                        format!(" ✖ [WORKER] Error: Couldn't spawn Command with args: {:?}. Error was: {}", args, err))
-                                }
-                            }else{
-                                match Command::new("blender")
-                                                       .args(args.clone())
-                                                       .stdout(Stdio::piped())
-                                                       .stderr(Stdio::piped())
-                                                       .spawn(){
-                                    Ok(c) => {
-                                        println!("{}", format!(" ⚟ [WORKER][{task_id}][{parent_id}][{short}] Dispatched Command", 
-                                            task_id=&task.id[..6], 
-                                            parent_id=&task.parent_id[..6],
-                                            short=task.command.short()).yellow());
-                                        self.command = Some(c);
-                                        
-                                        ExitStatus::Running
-                                    },
-                                    Err(err) => ExitStatus::Errored(
-                                        format!(" ✖ [WORKER] Error: Couldn't spawn Command with args: {:?}. Error was: {}", args, err))
+                    match spawn_blender(task, &blender.path, is_server){
+                        Ok(c) => {
+                            println!("{}", format!(" ⚟ [WORKER][{task_id}][{parent_id}][{short}] Dispatched Command in slot {slot} (Blender {blender})", 
+                                task_id=&task.id[..6], 
+                                parent_id=&task.parent_id[..6],
+                                short=task.command.short(),
+                                slot=i,
+                                blender=blender.name).yellow());
+                            // Remember which Blender rendered the Task
+                            task.add_data("blender", blender.name.as_str());
+                            task.add_data("blender-path", &blender.path.to_string_lossy());
+                            slot.render = Some(Render::new(c));
+                            // Capture the output of the render next to its frames
+                            let path = logs::log_path(outpath, &task.parent_id, &task.id);
+                            slot.log = match RenderLog::open(&path, log_max_bytes, log_rotations){
+                                Ok(log) => Some(log),
+                                Err(err) => {
+                                    errrun(format!("[{}] Couldn't open render log at {}: {}", &task.id[..6], path.to_string_lossy(), err));
+                                    None
                                 }
-                            }
+                            };
+                            ExitStatus::Running
                         },
-                        None => ExitStatus::Errored(format!(" ✖ [WORKER] Error: Couldn't split arguments for command: {:?}", task.command))
+                        Err((failure, err)) => ExitStatus::Errored(failure, err)
                     }
                 }else{
                     ExitStatus::None
                 }
             },
-            // when there is a command and a current task wait for the command to finish
-            Work{command: Some(ref mut child), current:Some(_task), ..} => {
-                let timeout = Duration::from_secs(1);
-                sleep(timeout);
-                match child.try_wait() {
+            // when there is a command and a task wait for the command to finish
+            Slot{render: Some(ref mut render), task: Some(_), ref mut progress, ref mut log, started, ..} => {
+                match render.try_wait() {
                     Ok(Some(status)) if status.success() => {
+                        process_output(render.finish(), progress, log);
                         ExitStatus::Finished
                     },
-                    Ok(Some(status))  => ExitStatus::Errored(format!(" ✖ [WORKER] Error: Command returned with status: {:?}", status)),
+                    Ok(Some(status))  => {
+                        process_output(render.finish(), progress, log);
+                        ExitStatus::Errored(Failure::ExitStatus, format!(" ✖ [WORKER] Error: Command returned with status: {:?}", status))
+                    },
                     Ok(None) => {
-                        process_stdout(child);
-                        ExitStatus::Running
+                        process_output(render.drain(), progress, log);
+                        // Kill renders that take too long or went silent
+                        match check_limits(render, started, timeout, stall_timeout){
+                            Some((failure, reason)) => {
+                                if let Err(err) = render.kill(){
+                                    errrun(format!("Couldn't kill render in slot {}: {}", i, err));
+                                }
+                                process_output(render.finish(), progress, log);
+                                ExitStatus::Errored(failure, reason)
+                            },
+                            None => ExitStatus::Running
+                        }
                     },
                     Err(err) => 
-                        ExitStatus::Errored(format!(" ✖ [WORKER] Error: waiting for spawned Command: {}", err)),
+                        ExitStatus::Errored(Failure::Other, format!(" ✖ [WORKER] Error: waiting for spawned Command: {}", err)),
                 }
             },
             // Everything else
             _ => ExitStatus::None
-        };
+        }
+    }
+}
 
-        // println!("Debug: We have a exitstatus of: {:?}", exitstatus);
 
-        match exitstatus{
-            ExitStatus::None => (),
-            ExitStatus::Running => {
-                match self.current{
-                    Some(ref mut c) if !c.is_running() => {
-                        c.start();
-                        // println!("Debug: Started Task because it wasn't running");
-                    },
-                    _ => ()
-                }
-            },
-            ExitStatus::Errored(err) => self.error_current(err, channel),
-            ExitStatus::Finished => self.finish_current(channel)
+
+/// Check a running render against the configured limits (in minutes, 0 means \
+/// no limit) and return the reason if it should be killed:
+/// - the render has been running longer than `timeout`
+/// - the render hasn't printed anything for `stall_timeout` (it hangs)
+fn check_limits(render: &Render, started: Option<DateTime<Utc>>, timeout: u64, stall_timeout: u64) -> Option<(Failure, String)>{
+    let now = Utc::now();
+    match started{
+        Some(started) if timeout > 0 && now - started > chrono::Duration::minutes(timeout as i64) => {
+            return Some((Failure::Timeout, format!(" ✖ [WORKER] Error: Render exceeded the timeout of {} minutes and was killed", timeout)));
+        },
+        _ => ()
+    }
+    if stall_timeout > 0 && now - render.last_output > chrono::Duration::minutes(stall_timeout as i64){
+        return Some((Failure::Stall, format!(" ✖ [WORKER] Error: Render stalled (no output for {} minutes) and was killed", stall_timeout)));
+    }
+    None
+}
+
+
+
+/// Spawn the given blender executable with the arguments from the Tasks command. \
+/// If we are in server mode assume we run linux and spawn with the gid "bender"
+fn spawn_blender(task: &Task, blender: &Path, is_server: bool) -> Result<std::process::Child, (Failure, String)>{
+    // Don't even try if the blendfile went missing
+    if let Some(blendfile) = task.data.get("blendfile"){
+        if !Path::new(blendfile).exists(){
+            return Err((Failure::MissingBlendfile, format!(" ✖ [WORKER] Error: The blendfile at {} is missing", blendfile)));
         }
     }
+
+    // Replace only first "blender " in command string
+    let s = task.command.to_string().unwrap().replacen("blender ", "", 1);
+    match shlex::split(&s){
+        Some(args) => {
+            let mut command = Command::new(blender);
+            command.args(args.clone())
+                   .stdout(Stdio::piped())
+                   .stderr(Stdio::piped());
+
+            if is_server && cfg!(unix){
+                use std::os::unix::process::CommandExt;
+                // Get bender gid when we are on a server
+                let mut cache = UsersCache::new();
+                let group = cache.get_group_by_name("bender").expect("There is no group called 'bender' please create the requeired users and groups!");
+                command.gid(group.gid());
+            }
+
+            // Put Blender in its own process group, so a Ctrl-C in the terminal \
+            // only reaches the worker, which then decides whether to drain or kill
+            #[cfg(unix)]
+            {
+                use std::os::unix::process::CommandExt;
+                command.process_group(0);
+            }
+
+            command.spawn()
+                   .map_err(|err| (Failure::Spawn, format!(" ✖ [WORKER] Error: Couldn't spawn {} with args: {:?}. Error was: {}", blender.to_string_lossy(), args, err)))
+        },
+        None => Err((Failure::Spawn, format!(" ✖ [WORKER] Error: Couldn't split arguments for command: {:?}", task.command)))
+    }
 }
 
 
@@ -190,32 +268,38 @@ impl Work{
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ExitStatus{
     Finished,
-    Errored(String),
+    Errored(Failure, String),
     Running,
     None
 }
 
 
 
-/// Process the stdout of spawned commands
-pub fn process_stdout(child:&mut std::process::Child){
-    match child.stdout{
-        Some(ref mut stdout) => {
-            let reader = BufReader::new(stdout);
-            reader.lines()
-                  .filter_map(|line| line.ok())
-                  .filter(|line| line.trim() != "")
-                  .for_each(|line| {
-                    let _message = format!("   [WORKER][COMMAND] {}", line).dimmed();
-                    
-                    // let term = Term::stdout();
-                    // let w = term.size().1 as usize;
-                    // let lines = measure_text_width(&message.to_string()) / w;
-                    // let _ = term.clear_last_lines(lines+1);
-                    
-                    // println!("{}", message);
-                  });
-        },
-        None => eprintln!("{}", " ✖ [WORKER] Error: Couldn't get a stdout".to_string().red())
+/// Process the output lines of spawned commands: update the given progress \
+/// with Blenders status lines and write every line to the render log
+pub fn process_output(lines: Vec<Output>, progress: &mut Progress, log: &mut Option<RenderLog>){
+    if let Some(ref mut render_log) = *log{
+        for output in lines.iter(){
+            if let Err(err) = render_log.write(output){
+                errrun(format!("Couldn't write to render log at {}: {}", render_log.path().to_string_lossy(), err));
+                break;
+            }
+        }
     }
-}
\ No newline at end of file
+
+    lines.iter()
+         .map(|output| output.line())
+         .filter(|line| line.trim() != "")
+         .for_each(|line| {
+            progress.update(line);
+
+            let _message = format!("   [WORKER][COMMAND] {}", line).dimmed();
+            
+            // let term = Term::stdout();
+            // let w = term.size().1 as usize;
+            // let lines = measure_text_width(&message.to_string()) / w;
+            // let _ = term.clear_last_lines(lines+1);
+            
+            // println!("{}", message);
+         });
+}
@@ -0,0 +1,231 @@
+//! The work::downloads module moves blendfile downloads off the main loop. A \
+//! bounded pool of background threads runs the requests (see \
+//! `work::requests::fetch_blendfile()`) and reports their progress and results \
+//! back via a channel, so heartbeats and renders of jobs whose blendfile is \
+//! there already keep going while a big scene is still downloading.
+//!
+//! While a download is in flight the job is marked as `Blend::Downloading` in \
+//! `Work::blendfiles`.
+
+use ::*;
+use std::thread;
+use std::thread::JoinHandle;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender, Receiver};
+use blend::Blend;
+use work::blendfiles::Blendfile;
+use work::requests::fetch_blendfile;
+use work::client::BenderClient;
+
+
+
+
+/// How much of a blendfile has been downloaded so far
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct DownloadProgress{
+    pub bytes: u64,
+    pub total: Option<u64>
+}
+
+impl DownloadProgress{
+    /// Create a new DownloadProgress with nothing downloaded
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Return the progress as a factor between 0.0 and 1.0, if the total size \
+    /// is known
+    pub fn fraction(&self) -> Option<f64>{
+        self.total.filter(|&total| total > 0)
+                  .map(|total| self.bytes as f64 / total as f64)
+    }
+}
+
+
+/// Everything a background thread needs to download a blendfile
+#[derive(Debug, Clone)]
+pub struct DownloadRequest{
+    pub job_id: String,
+    pub url: String,
+    pub client: BenderClient,
+    pub partpath: PathBuf,
+    /// Hashes of the files in the cache, these don't need to be downloaded
+    pub known_hashes: Vec<String>
+}
+
+
+/// The outcome of a successful download request
+#[derive(Debug, Clone)]
+pub enum Fetched{
+    /// The file has been downloaded to `part` and has the given hash
+    Downloaded{ part: PathBuf, hash: String },
+    /// The server announced a hash that is in the cache already
+    Cached{ hash: String }
+}
+
+
+/// A message sent from the background threads to `Work`
+#[derive(Debug, Clone)]
+pub enum DownloadEvent{
+    Progress{ job_id: String, progress: DownloadProgress },
+    Done{ job_id: String, result: Result<Fetched, String> }
+}
+
+
+
+
+/// A bounded pool of threads that run download requests
+#[derive(Debug)]
+pub struct DownloadPool{
+    requests: Sender<DownloadRequest>,
+    events: Receiver<DownloadEvent>,
+    workers: Vec<JoinHandle<()>>
+}
+
+
+impl DownloadPool{
+    /// Start a pool with the given number of threads (at least one)
+    pub fn new(size: usize) -> Self{
+        let (requests, request_receiver) = mpsc::channel::<DownloadRequest>();
+        let (event_sender, events) = mpsc::channel::<DownloadEvent>();
+        let request_receiver = Arc::new(Mutex::new(request_receiver));
+
+        let workers = (0..std::cmp::max(size, 1))
+            .map(|_|{
+                let request_receiver = request_receiver.clone();
+                let event_sender = event_sender.clone();
+                thread::spawn(move ||{
+                    loop{
+                        // Only hold the lock while waiting for the next request
+                        let request = match request_receiver.lock(){
+                            Ok(receiver) => receiver.recv(),
+                            Err(_) => break
+                        };
+                        let request = match request{
+                            Ok(request) => request,
+                            // The pool has been dropped
+                            Err(_) => break
+                        };
+                        let job_id = request.job_id.clone();
+                        let result = fetch_blendfile(&request, |progress|{
+                            let _ = event_sender.send(DownloadEvent::Progress{ job_id: job_id.clone(), progress });
+                        }).map_err(|err| format!("{}", err));
+                        if event_sender.send(DownloadEvent::Done{ job_id, result }).is_err(){
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        DownloadPool{
+            requests,
+            events,
+            workers
+        }
+    }
+
+    /// Queue a request for the next free thread. Returns false if the pool \
+    /// doesn't run anymore
+    pub fn dispatch(&self, request: DownloadRequest) -> bool{
+        self.requests.send(request).is_ok()
+    }
+
+    /// Return all events that arrived since the last call, without blocking
+    pub fn poll(&self) -> Vec<DownloadEvent>{
+        self.events.try_iter().collect()
+    }
+
+    /// Return the number of threads in the pool
+    pub fn size(&self) -> usize{
+        self.workers.len()
+    }
+}
+
+
+
+
+impl Work{
+    /// Returns true if there are blendfiles being downloaded
+    pub fn is_downloading(&self) -> bool{
+        self.blendfiles.values().any(|blend| blend.is_downloading())
+    }
+
+    /// Process the progress and results reported by the download threads. \
+    /// Finished downloads are moved into the cache and become \
+    /// `Blend::Downloaded`, failed ones are forgotten, so they get requested \
+    /// again once the rate limiter allows it
+    pub fn collect_downloads(&mut self){
+        let events = self.downloads.poll();
+        if events.is_empty(){
+            return;
+        }
+
+        for event in events{
+            match event{
+                DownloadEvent::Progress{ job_id, progress } => {
+                    if let Some(&mut Blend::Downloading{ progress: ref mut current }) = self.blendfiles.get_mut(&job_id){
+                        // Print a line for every 10 percent
+                        let step = |p: &DownloadProgress| p.fraction().map(|f| (f * 10.0) as usize);
+                        if step(&progress) > step(&*current){
+                            println!(" ⬇ [WORKER][      ][{}]          Downloading blendfile: {}",
+                                &job_id[..6], format_progress(&progress));
+                        }
+                        *current = progress;
+                    }
+                },
+                DownloadEvent::Done{ job_id, result } => {
+                    // The job has been dropped (e.g. canceled) while its file was downloading
+                    if !self.blendfiles.get(&job_id).map(|blend| blend.is_downloading()).unwrap_or(false){
+                        if let Ok(Fetched::Downloaded{ part, .. }) = result{
+                            let _ = fs::remove_file(part);
+                        }
+                        continue;
+                    }
+                    let path = match result{
+                        Ok(Fetched::Downloaded{ part, hash }) => self.cache.insert(&job_id, &hash, &part)
+                                                                           .map_err(|err| format!("Couldn't move the blendfile into the cache: {}", err)),
+                        Ok(Fetched::Cached{ hash }) => {
+                            println!(" ✔️ [WORKER][      ][{}]          Reused cached blendfile {}", &job_id[..6], hash.get(..12).unwrap_or(&hash));
+                            self.cache.link(&job_id, &hash)
+                                      .map_err(|err| format!("Couldn't reuse the cached blendfile: {}", err))
+                        },
+                        Err(err) => Err(err)
+                    };
+                    match path{
+                        Ok(path) => {
+                            // Files from the cache might have been optimized for \
+                            // another job already, optimize_blendfiles() checks that
+                            self.blendfiles.insert(job_id.clone(), Blend::Downloaded(Blendfile::new(&path)));
+                            self.last_download.set_last();
+                            let h = format!("Worker [{}] stored blendfile [{}] at {}",
+                                self.config.id, job_id, path.to_string_lossy());
+                            self.add_history(h.as_str());
+                        },
+                        Err(err) => {
+                            self.blendfiles.remove(&job_id);
+                            self.last_download.set_last_failed();
+                            errrun(format!("While downloading the blendfile for job [{}]: {}", &job_id[..6], err));
+                        }
+                    }
+                }
+            }
+        }
+
+        if !self.is_downloading() && self.blendfiles.values().all(|blend| blend.is_some()){
+            println!("{}", " ✔️ [WORKER] Downloaded all blendfiles".green());
+        }
+    }
+}
+
+
+
+
+/// Format the progress like `45% (900.0 MB of 2000.0 MB)`
+pub fn format_progress(progress: &DownloadProgress) -> String{
+    let mb = |bytes: u64| bytes as f64 / 1e6;
+    match (progress.fraction(), progress.total){
+        (Some(fraction), Some(total)) => format!("{:.0}% ({:.1} MB of {:.1} MB)", fraction * 100.0, mb(progress.bytes), mb(total)),
+        _ => format!("{:.1} MB", mb(progress.bytes))
+    }
+}
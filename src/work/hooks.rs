@@ -0,0 +1,370 @@
+//! The work::hooks module runs the pre-render hook pipeline on downloaded \
+//! blendfiles (see `work::optimize`). A hook is a python script that is run \
+//! with the Blender the job is rendered with:
+//! ```text
+//! blender -b file.blend --disable-autoexec --python-exit-code 1 --python hook.py
+//! ```
+//! The pipeline is set by the `hooks` table of the `WorkerConfig`:
+//! - `builtin`: the built-in hooks to run in this order (`scene-info` reports \
+//!   the render settings and inspects the scene, `optimize` switches Cycles \
+//!   to the GPU and saves the file)
+//! - `directory`: additional scripts, run after the built-in ones in the order \
+//!   of their file names (e.g. `10-denoise.py`), each named after its file
+//! - `timeout_seconds` and `timeouts`: how long a hook may run, by default and \
+//!   per hook name. A hook that takes longer is killed
+//!
+//! A hook reports its result as a single line of JSON on stdout:
+//! ```text
+//! BENDER-HOOK-RESULT:{"render_engine": "CYCLES", "resolution": [1920, 1080], "frame_range": [1, 250], "samples": 128}
+//! ```
+//! All fields are optional, `notes` (a list of strings) are printed, `report` \
+//! is a `SceneReport` (see `work::report`) and `error` fails the hook. A hook \
+//! that exits with a non-zero status or doesn't report a result fails as well, \
+//! and with it the whole pipeline.
+//!
+//! Every hook has a version (built-in hooks a number, scripts the hash of \
+//! their contents). The versions of all hooks make up the version of the \
+//! pipeline, which is stored with the results, so cached blendfiles run \
+//! through the pipeline again once it changed.
+
+use ::*;
+use std::collections::BTreeMap;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::io;
+use bender_job::Task;
+use bender_job::common::tempfile::NamedTempFile;
+use blend::Blend;
+use config::GenResult;
+use work::render::{Render, Output};
+use work::requests::sha256_hex;
+use work::report::SceneReport;
+
+/// Prefix of the line a hook reports its result with
+pub const RESULT_PREFIX: &str = "BENDER-HOOK-RESULT:";
+/// How long a hook may run by default
+const HOOK_TIMEOUT: u64 = 300;
+
+/// The built-in hooks as name, version and script. Bump the version whenever \
+/// a script changes, so cached blendfiles are processed again
+const BUILTIN_HOOKS: &[(&str, u32, &str)] = &[
+    ("scene-info", 2, include_str!("scene_info.py")),
+    ("optimize",   2, include_str!("optimize.py"))
+];
+
+
+
+
+/// The `hooks` table of the `WorkerConfig`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HookConfig{
+    /// The built-in hooks to run, in order
+    pub builtin: Vec<String>,
+    /// A directory with additional python scripts
+    pub directory: Option<PathBuf>,
+    /// Seconds a hook may run, unless it has its own timeout
+    pub timeout_seconds: u64,
+    /// Seconds the hook with the given name may run
+    pub timeouts: BTreeMap<String, u64>
+}
+
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl HookConfig{
+    /// Run all built-in hooks and nothing else
+    pub fn new() -> Self{
+        HookConfig{
+            builtin: BUILTIN_HOOKS.iter().map(|&(name, _, _)| name.to_string()).collect(),
+            directory: None,
+            timeout_seconds: HOOK_TIMEOUT,
+            timeouts: BTreeMap::new()
+        }
+    }
+
+    /// Return the timeout for the hook with the given name
+    fn timeout(&self, name: &str) -> Duration{
+        Duration::from_secs(*self.timeouts.get(name).unwrap_or(&self.timeout_seconds))
+    }
+}
+
+
+
+
+/// The render settings reported by the hooks
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SceneSettings{
+    pub render_engine: Option<String>,
+    /// Width and height of the frames in pixels
+    pub resolution: Option<(u32, u32)>,
+    /// First and last frame of the scene
+    pub frame_range: Option<(i64, i64)>,
+    pub samples: Option<u32>
+}
+
+
+impl SceneSettings{
+    /// Take over everything the other settings know about
+    pub fn merge(&mut self, other: &SceneSettings){
+        if other.render_engine.is_some(){
+            self.render_engine = other.render_engine.clone();
+        }
+        self.resolution = other.resolution.or(self.resolution);
+        self.frame_range = other.frame_range.or(self.frame_range);
+        self.samples = other.samples.or(self.samples);
+    }
+}
+
+
+/// What a hook prints after the `RESULT_PREFIX`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct HookOutput{
+    #[serde(flatten)]
+    pub scene: SceneSettings,
+    pub notes: Vec<String>,
+    pub report: Option<SceneReport>,
+    pub error: Option<String>
+}
+
+
+/// The result of a single hook
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HookRun{
+    pub name: String,
+    pub version: String,
+    pub milliseconds: u64,
+    pub scene: SceneSettings,
+    pub notes: Vec<String>
+}
+
+
+/// The results of a pipeline run, stored on the `Blendfile` (and in the cache)
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PipelineResult{
+    /// The version of the pipeline that produced this
+    pub version: String,
+    pub hooks: Vec<HookRun>,
+    /// The settings reported by all hooks, later hooks win
+    pub scene: SceneSettings,
+    /// The last scene report of a hook
+    #[serde(default)]
+    pub report: Option<SceneReport>
+}
+
+
+
+
+/// Where the script of a hook comes from
+#[derive(Debug, Clone)]
+enum HookSource{
+    Builtin(&'static str),
+    Script(PathBuf)
+}
+
+
+/// A single named hook
+#[derive(Debug, Clone)]
+pub struct Hook{
+    pub name: String,
+    pub version: String,
+    pub timeout: Duration,
+    source: HookSource
+}
+
+
+impl Hook{
+    /// Run the hook on the blendfile at the given path and return its output
+    pub fn run(&self, blender: &Path, blendpath: &Path) -> GenResult<HookOutput>{
+        // Built-in scripts have to be written to disk first, keep the \
+        // tempfile around until blender is done with it
+        let (script, _tempfile) = match self.source{
+            HookSource::Builtin(source) => {
+                let mut tempfile = NamedTempFile::new()?;
+                io::copy(&mut source.as_bytes(), &mut tempfile)?;
+                (tempfile.path().to_path_buf(), Some(tempfile))
+            },
+            HookSource::Script(ref path) => (path.clone(), None)
+        };
+
+        let child = Command::new(blender).arg("-b")
+                                         .arg(blendpath)
+                                         .arg("--disable-autoexec")
+                                         .args(&["--python-exit-code", "1"])
+                                         .arg("--python")
+                                         .arg(&script)
+                                         .env("BENDER_HOOK_NAME", &self.name)
+                                         .stdout(Stdio::piped())
+                                         .stderr(Stdio::piped())
+                                         .spawn()
+                                         .map_err(|err| format!("Couldn't spawn {}: {}", blender.to_string_lossy(), err))?;
+
+        // Read the output in the background, so the pipes can't fill up
+        let mut render = Render::new(child);
+        let started = Instant::now();
+        let mut lines = Vec::new();
+        let status = loop{
+            lines.extend(render.drain());
+            match render.try_wait()?{
+                Some(status) => break status,
+                None if started.elapsed() > self.timeout => {
+                    render.kill()?;
+                    return Err(From::from(format!("Hook \"{}\" timed out after {} seconds", self.name, self.timeout.as_secs())));
+                },
+                None => thread::sleep(Duration::from_millis(100))
+            }
+        };
+        lines.extend(render.finish());
+
+        if !status.success(){
+            return Err(From::from(format!("Hook \"{}\" failed with {}: {}", self.name, status, last_lines(&lines, 5))));
+        }
+        let output = lines.iter()
+                          .filter_map(|line| match line{
+                              Output::Stdout(line) if line.starts_with(RESULT_PREFIX) => Some(&line[RESULT_PREFIX.len()..]),
+                              _ => None
+                          })
+                          .last()
+                          .ok_or_else(|| format!("Hook \"{}\" didn't report a result: {}", self.name, last_lines(&lines, 5)))?;
+        let output: HookOutput = serde_json::from_str(output)
+                                            .map_err(|err| format!("Hook \"{}\" reported an invalid result: {}", self.name, err))?;
+        match output.error{
+            Some(ref err) => Err(From::from(format!("Hook \"{}\" reported an error: {}", self.name, err))),
+            None => Ok(output)
+        }
+    }
+}
+
+
+/// Join the last `n` lines of the output for error messages
+fn last_lines(lines: &[Output], n: usize) -> String{
+    let skip = lines.len().saturating_sub(n);
+    lines[skip..].iter()
+                 .map(|line| line.line().trim())
+                 .collect::<Vec<&str>>()
+                 .join(" | ")
+}
+
+
+
+
+/// The ordered hooks that are run on every downloaded blendfile
+#[derive(Debug, Clone)]
+pub struct Pipeline{
+    pub hooks: Vec<Hook>
+}
+
+
+impl Pipeline{
+    /// Build the pipeline from the config. Unknown built-in hooks and an \
+    /// unreadable hook directory are errors
+    pub fn from_config(config: &HookConfig) -> GenResult<Self>{
+        let mut hooks = Vec::new();
+        for name in config.builtin.iter(){
+            match BUILTIN_HOOKS.iter().find(|&&(builtin, _, _)| builtin == name.as_str()){
+                Some(&(builtin, version, source)) => hooks.push(Hook{
+                    name: builtin.to_string(),
+                    version: version.to_string(),
+                    timeout: config.timeout(builtin),
+                    source: HookSource::Builtin(source)
+                }),
+                None => return Err(From::from(format!("There is no built-in hook called \"{}\"", name)))
+            }
+        }
+
+        if let Some(ref directory) = config.directory{
+            let mut scripts: Vec<PathBuf> = fs::read_dir(directory)
+                .map_err(|err| format!("Couldn't read the hook directory at {}: {}", directory.to_string_lossy(), err))?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_file() && path.extension().map(|ext| ext == "py").unwrap_or(false))
+                .collect();
+            scripts.sort();
+            for path in scripts{
+                let name = path.file_stem().unwrap().to_string_lossy().to_string();
+                let version = sha256_hex(&path)?;
+                hooks.push(Hook{
+                    version: version[..12].to_string(),
+                    timeout: config.timeout(&name),
+                    name,
+                    source: HookSource::Script(path)
+                });
+            }
+        }
+        Ok(Pipeline{ hooks })
+    }
+
+    /// The version of the pipeline, e.g. `scene-info@1,optimize@2,denoise@3f2a...`
+    pub fn version(&self) -> String{
+        self.hooks.iter()
+                  .map(|hook| format!("{}@{}", hook.name, hook.version))
+                  .collect::<Vec<String>>()
+                  .join(",")
+    }
+
+    /// Run all hooks in order on the blendfile at the given path with the \
+    /// given blender executable. The first failing hook stops the pipeline
+    pub fn run(&self, blender: &Path, blendpath: &Path) -> GenResult<PipelineResult>{
+        let mut result = PipelineResult{
+            version: self.version(),
+            ..Default::default()
+        };
+        for hook in self.hooks.iter(){
+            let started = Instant::now();
+            let output = hook.run(blender, blendpath)?;
+            for note in output.notes.iter(){
+                notemsg(format!("[{}] {}", hook.name, note));
+            }
+            result.scene.merge(&output.scene);
+            if output.report.is_some(){
+                result.report = output.report;
+            }
+            let elapsed = started.elapsed();
+            result.hooks.push(HookRun{
+                name: hook.name.clone(),
+                version: hook.version.clone(),
+                milliseconds: elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()),
+                scene: output.scene,
+                notes: output.notes
+            });
+        }
+        Ok(result)
+    }
+}
+
+
+
+
+impl Work{
+    /// Add the results of the hook pipeline to the data of all Tasks of \
+    /// optimized blendfiles, as `scene-settings` (JSON) and `hook-pipeline` \
+    /// (the version of the pipeline)
+    pub fn add_hook_results_to_tasks(&mut self){
+        let blendfiles = &self.blendfiles;
+        for task in self.tasks.iter_mut().filter(|task| !task.data.contains_key("hook-pipeline")){
+            if let Some(&Blend::Optimized(ref bf)) = blendfiles.get(&task.parent_id){
+                if let Some(ref results) = bf.hook_results{
+                    add_hook_results(task, results);
+                }
+            }
+        }
+    }
+}
+
+
+/// Add the results of a pipeline run to the data of the Task
+fn add_hook_results(task: &mut Task, results: &PipelineResult){
+    match serde_json::to_string(&results.scene){
+        Ok(json) => task.add_data("scene-settings", json.as_str()),
+        Err(err) => errrun(format!("[{}] Couldn't serialize the scene settings: {}", &task.id[..6], err))
+    }
+    task.add_data("hook-pipeline", results.version.as_str());
+}
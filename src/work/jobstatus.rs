@@ -0,0 +1,81 @@
+//! The work::jobstatus module turns the status of a Job into a `JobState` the \
+//! worker can act on. In independent mode flaskbender answers \
+//! `GET /job/worker/status/<job-id>` with the JSON serialized \
+//! `bender_job::Status` of the Job (the same as the `status` field of its \
+//! `data.json`), in server mode the `data.json` is read directly.
+
+use ::*;
+use std::fmt;
+use bender_job::{Status, JobStatus};
+
+
+
+
+/// The state of a Job as far as the worker is concerned
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum JobState{
+    /// No status has been received (yet)
+    Unknown,
+    Queued,
+    Running,
+    Paused,
+    Canceled,
+    Errored,
+    Finished
+}
+
+impl Default for JobState {
+    fn default() -> Self {
+        JobState::Unknown
+    }
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+
+impl JobState{
+    /// Classify a `bender_job::Status`
+    pub fn from_status(status: &Status) -> Self{
+        match *status{
+            Status::Job(ref job) => match *job{
+                JobStatus::Queued   => JobState::Queued,
+                JobStatus::Running  => JobState::Running,
+                JobStatus::Paused   => JobState::Paused,
+                JobStatus::Canceled => JobState::Canceled,
+                JobStatus::Errored  => JobState::Errored,
+                JobStatus::Finished => JobState::Finished
+            },
+            // A request that is still being checked will be queued as a Job
+            Status::Request(_) => JobState::Queued
+        }
+    }
+
+    pub fn is_finished(&self) -> bool{
+        *self == JobState::Finished
+    }
+
+    pub fn is_paused(&self) -> bool{
+        *self == JobState::Paused
+    }
+
+    pub fn is_canceled(&self) -> bool{
+        *self == JobState::Canceled
+    }
+
+    pub fn is_errored(&self) -> bool{
+        *self == JobState::Errored
+    }
+
+    /// Returns true if nothing more will happen with the Job (it is finished, \
+    /// canceled or errored)
+    pub fn is_ended(&self) -> bool{
+        match *self{
+            JobState::Finished | JobState::Canceled | JobState::Errored => true,
+            _ => false
+        }
+    }
+}
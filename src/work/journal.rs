@@ -0,0 +1,395 @@
+//! The work::journal module persists the state of `Work` to a journal file, \
+//! so a worker that crashed or got restarted after rendering frames can still \
+//! stat, upload and report them instead of losing them.
+//!
+//! The journal is a JSON file stored in the app data dir (or next to the \
+//! blendfiles when running on the server). It is written atomically (to a \
+//! `.part` file that is then renamed) whenever the state changes.
+//!
+//! Only Tasks that have finished rendering are resumed from the journal: \
+//! their deliveries have already been acknowledged, so nobody else will render \
+//! them again. Deliveries of Tasks that were queued or running got requeued by \
+//! the broker as soon as the connection dropped, so they will simply arrive \
+//! again (their delivery tags are meaningless on a new connection anyways). \
+//! Tasks whose frames have all been uploaded are done for good and are left \
+//! out of the journal, so it doesn't grow with every Task ever rendered.
+
+use ::*;
+use std::io;
+use std::io::Write;
+use std::collections::BTreeMap;
+use std::fs::File;
+use bender_job::{Task, Command, FrameMap};
+use blend::Blend;
+use config::{WorkerConfig, GenResult};
+use work::blendfiles::Blendfile;
+use work::jobstatus::JobState;
+use work::hooks::PipelineResult;
+use chrono::{Utc, DateTime, Duration};
+
+
+
+
+/// A snapshot of the state of `Work` as written to the journal file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Journal{
+    pub worker_id: Uuid,
+    pub written: DateTime<Utc>,
+    pub tasks: Vec<Task>,
+    pub blendfiles: BTreeMap<String, BlendRecord>,
+    pub parent_jobs: BTreeMap<String, JobState>
+}
+
+
+/// A serializable version of a `Blend` (chrono Durations can't be serialized, \
+/// so the frame durations are stored in milliseconds)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlendRecord{
+    pub path: PathBuf,
+    pub optimized: bool,
+    pub creation: DateTime<Utc>,
+    pub lastaccess: DateTime<Utc>,
+    pub frames_rendered: usize,
+    pub frame_durations_ms: Vec<i64>,
+    #[serde(default)]
+    pub hook_results: Option<PipelineResult>
+}
+
+
+impl BlendRecord{
+    /// Create a record from a Blend. Returns None for `Blend::None` and \
+    /// downloads that are still in flight
+    pub fn from_blend(blend: &Blend) -> Option<Self>{
+        let (blendfile, optimized) = match *blend{
+            Blend::Optimized(ref b) => (b, true),
+            Blend::Downloaded(ref b) => (b, false),
+            Blend::Downloading{..} | Blend::None => return None
+        };
+        Some(BlendRecord{
+            path: blendfile.path.clone(),
+            optimized,
+            creation: blendfile.creation,
+            lastaccess: blendfile.lastaccess,
+            frames_rendered: blendfile.frames_rendered,
+            frame_durations_ms: blendfile.frame_durations.iter()
+                                                         .map(|d| d.num_milliseconds())
+                                                         .collect(),
+            hook_results: blendfile.hook_results.clone()
+        })
+    }
+
+    /// Turn the record back into a Blend
+    pub fn to_blend(&self) -> Blend{
+        let mut blendfile = Blendfile::new(self.path.clone());
+        blendfile.creation = self.creation;
+        blendfile.lastaccess = self.lastaccess;
+        blendfile.frames_rendered = self.frames_rendered;
+        blendfile.frame_durations = self.frame_durations_ms.iter()
+                                                           .map(|&ms| Duration::milliseconds(ms))
+                                                           .collect();
+        blendfile.hook_results = self.hook_results.clone();
+        if self.optimized{
+            Blend::Optimized(blendfile)
+        }else{
+            Blend::Downloaded(blendfile)
+        }
+    }
+}
+
+
+
+
+/// Returns true if the Task has finished rendering, but not all of its \
+/// frames have been uploaded yet
+pub fn needs_resume(task: &Task) -> bool{
+    task.is_finished() && match task.command{
+        Command::Blender(ref b) => !b.frame.all_uploaded(),
+        _ => false
+    }
+}
+
+
+/// Returns true if the Task has finished rendering and all of its frames \
+/// have been uploaded
+fn is_done(task: &Task) -> bool{
+    task.is_finished() && !needs_resume(task)
+}
+
+
+
+
+impl Journal{
+    /// Read the journal at the given path
+    pub fn load<P>(path: P) -> GenResult<Self> where P: AsRef<Path>{
+        let file = File::open(path.as_ref())?;
+        let journal = serde_json::from_reader(file)?;
+        Ok(journal)
+    }
+
+    /// Returns true if there are Tasks in the journal that need to be resumed
+    pub fn has_work(&self) -> bool{
+        self.tasks.iter().any(needs_resume)
+    }
+}
+
+
+
+
+/// Return the path to the journal file for the given config. This is in the \
+/// app data dir, or next to the blendfiles if there is none (e.g. on a server)
+pub fn journal_path(config: &WorkerConfig) -> PathBuf{
+    let data_dir = if config.mode.is_independent() {
+        app_dir(AppDataType::UserData, &APP_INFO, "/").ok()
+    } else {
+        None
+    };
+    let mut path = data_dir.unwrap_or_else(|| config.blendpath.clone());
+    path.push("journal.json");
+    path
+}
+
+
+/// Returns true if there is a readable journal at the path for the given \
+/// config that contains Tasks to resume
+pub fn has_journal(config: &WorkerConfig) -> bool{
+    match Journal::load(journal_path(config)){
+        Ok(journal) => journal.has_work(),
+        Err(_) => false
+    }
+}
+
+
+/// Write the bytes to a `.part` file next to the path and rename it to the \
+/// path, so a crash never leaves a half written journal behind
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()>{
+    let mut part = path.as_os_str().to_os_string();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    {
+        let mut file = File::create(&part)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+    }
+    fs::rename(&part, path)
+}
+
+
+
+
+impl Work{
+    /// Return a snapshot of the current state. Tasks in render slots are \
+    /// included as well, Tasks that are done for good are not
+    pub fn journal(&self) -> Journal{
+        let tasks = self.tasks.iter()
+                              .chain(self.slots.iter().filter_map(|slot| slot.task.as_ref()))
+                              .filter(|t| !is_done(t))
+                              .cloned()
+                              .collect();
+        let blendfiles = self.blendfiles.iter()
+                                        .filter_map(|(id, blend)| BlendRecord::from_blend(blend).map(|record| (id.clone(), record)))
+                                        .collect();
+        Journal{
+            worker_id: self.config.id,
+            written: Utc::now(),
+            tasks,
+            blendfiles,
+            parent_jobs: self.parent_jobs.iter()
+                                         .map(|(id, state)| (id.clone(), *state))
+                                         .collect()
+        }
+    }
+
+
+    /// Write the current state to the journal file, if it changed since the \
+    /// last write
+    pub fn write_journal(&mut self){
+        let journal = self.journal();
+        // Leave out the timestamp, it alone shouldn't trigger a write
+        let snapshot = match serde_json::to_string(&(&journal.tasks, &journal.blendfiles, &journal.parent_jobs)){
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                errrun(format!("Couldn't serialize the journal: {}", err));
+                return;
+            }
+        };
+        if self.last_journal.as_ref() == Some(&snapshot){
+            return;
+        }
+
+        let result = serde_json::to_vec_pretty(&journal)
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+                        .and_then(|bytes| write_atomically(&self.journal_path, &bytes));
+        match result{
+            Ok(_) => self.last_journal = Some(snapshot),
+            Err(err) => errrun(format!("Couldn't write the journal to {}: {}", self.journal_path.to_string_lossy(), err))
+        }
+    }
+
+
+    /// Resume the finished Tasks that still have frames to upload (and their \
+    /// blendfiles and parent jobs) from the journal file, if there is one
+    pub fn restore_journal(&mut self){
+        if !self.journal_path.exists(){
+            return;
+        }
+        let journal = match Journal::load(&self.journal_path){
+            Ok(journal) => journal,
+            Err(err) => {
+                errrun(format!("Couldn't read the journal at {}, starting fresh: {}", self.journal_path.to_string_lossy(), err));
+                return;
+            }
+        };
+
+        let mut resumed = 0;
+        for mut task in journal.tasks.into_iter().filter(needs_resume){
+            // The tag belonged to the old connection
+            task.data.remove("task-delivery-tag");
+            self.parent_jobs.entry(task.parent_id.clone())
+                            .or_insert_with(|| journal.parent_jobs.get(&task.parent_id).cloned().unwrap_or_default());
+            if let Some(record) = journal.blendfiles.get(&task.parent_id){
+                if record.path.exists(){
+                    self.blendfiles.entry(task.parent_id.clone())
+                                   .or_insert_with(|| record.to_blend());
+                }
+            }
+            if !self.tasks.iter().any(|t| t.id == task.id){
+                self.tasks.push(task);
+                resumed += 1;
+            }
+        }
+
+        if resumed > 0{
+            okrun(format!("Resumed {} finished Task(s) from the journal written at {}", resumed, journal.written.to_rfc3339()));
+        }
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use work::downloads::DownloadProgress;
+
+    /// Return a fresh directory below the temp dir
+    fn scratch_dir() -> PathBuf{
+        let dir = env::temp_dir().join(format!("bender-worker-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Return a Task of the given job that finished rendering a single frame
+    fn finished_task(job_id: &str) -> Task{
+        let mut task = Task::new_blender_single(1, "PNG".to_string());
+        task.parent_id = job_id.to_string();
+        task.finish();
+        task
+    }
+
+    #[test]
+    fn blend_record_round_trips(){
+        let mut blendfile = Blendfile::new("/tmp/scene.blend");
+        blendfile.add_frame(Duration::milliseconds(1500));
+        blendfile.add_frame(Duration::milliseconds(2500));
+        blendfile.hook_results = Some(PipelineResult{ version: "scene-info@2".to_string(), ..Default::default() });
+
+        let record = BlendRecord::from_blend(&Blend::Optimized(blendfile.clone())).unwrap();
+        let json = serde_json::to_string(&record).unwrap();
+        let record: BlendRecord = serde_json::from_str(&json).unwrap();
+        assert_eq!(record.frame_durations_ms, vec![1500, 2500]);
+
+        match record.to_blend(){
+            Blend::Optimized(restored) => {
+                assert_eq!(restored.path, blendfile.path);
+                assert_eq!(restored.creation, blendfile.creation);
+                assert_eq!(restored.frames_rendered, blendfile.frames_rendered);
+                assert_eq!(restored.frame_durations, blendfile.frame_durations);
+                assert_eq!(restored.hook_results, blendfile.hook_results);
+            },
+            other => panic!("Expected an optimized blendfile, got {:?}", other)
+        }
+
+        let record = BlendRecord::from_blend(&Blend::Downloaded(Blendfile::new("/tmp/scene.blend"))).unwrap();
+        assert!(!record.optimized);
+        assert!(!record.to_blend().is_optimized());
+        assert!(BlendRecord::from_blend(&Blend::Downloading{ progress: DownloadProgress::new() }).is_none());
+        assert!(BlendRecord::from_blend(&Blend::None).is_none());
+    }
+
+    #[test]
+    fn only_finished_tasks_with_frames_to_upload_are_resumed(){
+        let job_id = Uuid::new_v4().to_string();
+        let mut queued = Task::new_blender_single(1, "PNG".to_string());
+        queued.parent_id = job_id.clone();
+        assert!(!needs_resume(&queued));
+        assert!(!is_done(&queued));
+
+        let mut task = finished_task(&job_id);
+        assert!(needs_resume(&task));
+        assert!(!is_done(&task));
+
+        if let Command::Blender(ref mut b) = task.command{
+            b.set_all_uploaded().unwrap();
+        }
+        assert!(!needs_resume(&task));
+        assert!(is_done(&task));
+    }
+
+    #[test]
+    fn restore_journal_strips_delivery_tags_and_deduplicates(){
+        let dir = scratch_dir();
+        let mut config = WorkerConfig::new();
+        config.blendpath = dir.join("blend");
+        config.outpath = dir.join("frames");
+        fs::create_dir_all(&config.blendpath).unwrap();
+        let mut work = Work::new(config);
+        work.journal_path = dir.join("journal.json");
+
+        let job_id = Uuid::new_v4().to_string();
+        let blendpath = work.config.blendpath.join("scene.blend");
+        fs::write(&blendpath, b"").unwrap();
+        let mut task = finished_task(&job_id);
+        task.add_data("task-delivery-tag", "7");
+        let mut queued = Task::new_blender_single(2, "PNG".to_string());
+        queued.parent_id = job_id.clone();
+
+        let mut blendfiles = BTreeMap::new();
+        blendfiles.insert(job_id.clone(), BlendRecord::from_blend(&Blend::Optimized(Blendfile::new(&blendpath))).unwrap());
+        let mut parent_jobs = BTreeMap::new();
+        parent_jobs.insert(job_id.clone(), JobState::Running);
+        let journal = Journal{
+            worker_id: work.config.id,
+            written: Utc::now(),
+            tasks: vec![task.clone(), task.clone(), queued],
+            blendfiles,
+            parent_jobs
+        };
+        fs::write(&work.journal_path, serde_json::to_vec(&journal).unwrap()).unwrap();
+
+        work.restore_journal();
+        assert_eq!(work.tasks.len(), 1);
+        assert_eq!(work.tasks[0].id, task.id);
+        assert!(!work.tasks[0].data.contains_key("task-delivery-tag"));
+        assert!(work.blendfiles.get(&job_id).map(|blend| blend.is_optimized()).unwrap_or(false));
+        assert_eq!(work.job_state(job_id.as_str()), JobState::Running);
+
+        // Restoring again doesn't duplicate anything
+        work.restore_journal();
+        assert_eq!(work.tasks.len(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn write_atomically_replaces_the_file_and_leaves_no_part_behind(){
+        let dir = scratch_dir();
+        let path = dir.join("journal.json");
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second".to_vec());
+        assert!(!dir.join("journal.json.part").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
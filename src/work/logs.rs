@@ -0,0 +1,160 @@
+//! The work::logs module captures the output of each render into a log file \
+//! next to the Tasks frames (`<outpath>/<job-id>/<task-id>.log`). Once a log \
+//! grows beyond the configured size it is rotated to `<task-id>.log.1` (and \
+//! so on), keeping only a configured number of old logs around.
+
+use ::*;
+use std::io;
+use std::io::{Write, BufRead, BufReader};
+use std::fs::{File, OpenOptions};
+use work::render::Output;
+
+
+
+
+/// A log file for a single render, that knows how much has been written to it
+#[derive(Debug)]
+pub struct RenderLog{
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    rotations: usize
+}
+
+
+impl RenderLog{
+    /// Open (or create) the log at the given path for appending. `max_bytes` \
+    /// is the size at which the log gets rotated, `rotations` the number of \
+    /// rotated logs to keep
+    pub fn open<P>(path: P, max_bytes: u64, rotations: usize) -> io::Result<Self> where P: Into<PathBuf>{
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(RenderLog{
+            path,
+            file,
+            written,
+            max_bytes,
+            rotations
+        })
+    }
+
+    /// Return the path of the current log file
+    pub fn path(&self) -> &Path{
+        &self.path
+    }
+
+    /// Append a line of output to the log, rotating it if it grew too large. \
+    /// Lines from stderr are marked as such
+    pub fn write(&mut self, output: &Output) -> io::Result<()>{
+        let line = match output{
+            Output::Stdout(line) => format!("{}\n", line),
+            Output::Stderr(line) => format!("[stderr] {}\n", line)
+        };
+        if self.max_bytes > 0 && self.written + line.len() as u64 > self.max_bytes{
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    /// Move `<task-id>.log.n` to `<task-id>.log.n+1` for every kept log, move the \
+    /// current log to `<task-id>.log.1` and start over with a empty one
+    fn rotate(&mut self) -> io::Result<()>{
+        if self.rotations > 0{
+            for n in (1..self.rotations).rev(){
+                let from = rotated_path(&self.path, n);
+                if from.exists(){
+                    fs::rename(&from, rotated_path(&self.path, n+1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Return the last n lines of the current log
+    pub fn tail(&self, n: usize) -> String{
+        tail(&self.path, n)
+    }
+}
+
+
+
+
+/// Return the path of the log for the Task with the given ids
+pub fn log_path<P>(outpath: P, parent_id: &str, task_id: &str) -> PathBuf where P: Into<PathBuf>{
+    let mut path = outpath.into();
+    path.push(parent_id);
+    path.push(format!("{}.log", task_id));
+    path
+}
+
+
+/// Return the path of the nth rotated log (e.g. `<task-id>.log.1`)
+pub fn rotated_path(path: &Path, n: usize) -> PathBuf{
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", n));
+    path.with_file_name(name)
+}
+
+
+/// Search all job directories in the outpath for logs of Tasks whose id starts \
+/// with the given id (so the short ids printed by the worker work as well). \
+/// Returns the paths of the current logs
+pub fn find_logs<P>(outpath: P, task_id: &str) -> io::Result<Vec<PathBuf>> where P: Into<PathBuf>{
+    let outpath = outpath.into();
+    let mut found = Vec::new();
+    for job_dir in fs::read_dir(&outpath)?{
+        let job_dir = job_dir?.path();
+        if !job_dir.is_dir(){
+            continue;
+        }
+        for entry in fs::read_dir(&job_dir)?{
+            let path = entry?.path();
+            let is_match = match (path.file_stem(), path.extension()){
+                (Some(stem), Some(ext)) => ext == "log" && stem.to_string_lossy().starts_with(task_id),
+                _ => false
+            };
+            if is_match{
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+
+/// Delete all render logs (including rotated ones) in the given job directory
+pub fn remove_logs<P>(job_dir: P) -> io::Result<()> where P: AsRef<Path>{
+    for entry in fs::read_dir(job_dir.as_ref())?{
+        let path = entry?.path();
+        let is_log = path.file_name()
+                         .map(|name| name.to_string_lossy().contains(".log"))
+                         .unwrap_or(false);
+        if path.is_file() && is_log{
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+
+/// Return the last n lines of the file at the given path
+pub fn tail<P>(path: P, n: usize) -> String where P: AsRef<Path>{
+    match File::open(path.as_ref()){
+        Ok(file) => {
+            let lines: Vec<String> = BufReader::new(file).lines()
+                                                         .filter_map(|line| line.ok())
+                                                         .collect();
+            let skip = lines.len().saturating_sub(n);
+            lines[skip..].join("\n")
+        },
+        Err(_) => String::new()
+    }
+}
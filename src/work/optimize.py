@@ -1,41 +1,42 @@
-# This script is meant to be run from within blender
+# Built-in hook "optimize": this script is meant to be run from within blender.
+# It switches Cycles to the GPU if there is one and saves the file. Errors are
+# not caught, so blender exits with a non-zero status (--python-exit-code)
 print("Started running optimize.py")
 import bpy
+import json
 
 
-try:
-    # Get current Scene
-    scene = bpy.context.scene
-except:
-    print("Error: Couldn't get bpy.context.scene")
+scene = bpy.context.scene
+notes = []
 
+# bpy.context.user_preferences was renamed to bpy.context.preferences in 2.80
+preferences = getattr(bpy.context, "preferences", None) or bpy.context.user_preferences
 
 
-try:
-    # Check if Cycles is used
-    renderer = bpy.context.scene.render.engine
-    uses_cycles = renderer == 'CYCLES'
-except:
-    print("Error: couldn't get bpy.context.scene.render.engine")
-
-cuda = False
-
 # Try to switch to GPU and CUDA if cycles is used
-if uses_cycles:
-    prefs = bpy.context.user_preferences.addons['cycles'].preferences
-    if len(prefs.devices) > 0:
-        try:
-            bpy.context.user_preferences.addon['cycles'].preferences.compute_device_type = 'CUDA'
-            cuda = True
-        except:
-            if cuda:
-                try:
-                    scene.cycles.device = 'GPU'
-                except:
-                    scene.render.tile_x = 512
-                    scene.render.tile_y = 512
+if scene.render.engine == 'CYCLES' and 'cycles' in preferences.addons:
+    cycles = preferences.addons['cycles'].preferences
+    # Since 2.80 the device list is only filled on request
+    if hasattr(cycles, "get_devices"):
+        cycles.get_devices()
+    devices = [device for device in getattr(cycles, "devices", []) if device.type == 'CUDA']
+    if len(devices) > 0:
+        cycles.compute_device_type = 'CUDA'
+        for device in devices:
+            device.use = True
+        scene.cycles.device = 'GPU'
+        # Large tiles are faster on the GPU (tiles are gone since 3.0)
+        if hasattr(scene.render, "tile_x"):
+            scene.render.tile_x = 512
+            scene.render.tile_y = 512
+        notes.append("Rendering Cycles on {} CUDA device(s)".format(len(devices)))
+    else:
+        notes.append("Found no CUDA device, rendering Cycles on the CPU")
 
 
 
 # Overwrite the file
-bpy.ops.wm.save_as_mainfile(filepath=bpy.data.filepath, copy=True)
+if 'FINISHED' not in bpy.ops.wm.save_as_mainfile(filepath=bpy.data.filepath, copy=True):
+    raise RuntimeError("Couldn't save the optimized blendfile")
+
+print("BENDER-HOOK-RESULT:" + json.dumps({"notes": notes}))
@@ -1,11 +1,18 @@
+//! The work::optimize module runs the hook pipeline (see `work::hooks`) on \
+//! downloaded blendfiles. A hook may run for minutes, so the pipeline runs on \
+//! a background thread (the `Optimizer`) and `Work::update()` only dispatches \
+//! the jobs and collects their results, like with `work::downloads`.
+
 use ::*;
 use std::collections::HashMap;
+use std::thread;
+use std::sync::mpsc::{self, Sender, Receiver};
 use bender_config::GenResult;
 use bender_job::Task;
 use blend::Blend;
-use std::io;
-use std::process::Command;
-use bender_job::common::tempfile::NamedTempFile;
+use work::hooks::{Pipeline, PipelineResult};
+use work::retry::Failure;
+use work::transport::Transport;
 
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
@@ -13,8 +20,95 @@ use std::os::unix::fs::PermissionsExt;
 
 
 
+/// Everything the background thread needs to run the pipeline on a blendfile
+#[derive(Debug, Clone)]
+pub struct OptimizeRequest{
+    pub job_id: String,
+    pub pipeline: Pipeline,
+    pub blendpath: PathBuf,
+    pub blender: PathBuf
+}
+
+
+/// The result of a pipeline run, sent from the background thread to `Work`
+#[derive(Debug, Clone)]
+pub struct OptimizeDone{
+    pub job_id: String,
+    pub result: Result<PipelineResult, String>
+}
+
+
+
+
+/// A background thread that runs the hook pipeline one blendfile at a time
+#[derive(Debug)]
+pub struct Optimizer{
+    requests: Sender<OptimizeRequest>,
+    events: Receiver<OptimizeDone>,
+    /// Jobs whose blendfile is being optimized right now or waits for it, \
+    /// with the path of the blendfile
+    in_flight: HashMap<String, PathBuf>
+}
+
+
+impl Optimizer{
+    /// Start the background thread
+    pub fn new() -> Self{
+        let (requests, request_receiver) = mpsc::channel::<OptimizeRequest>();
+        let (event_sender, events) = mpsc::channel::<OptimizeDone>();
+
+        thread::spawn(move ||{
+            // Ends once the Optimizer has been dropped
+            for request in request_receiver.iter(){
+                let result = optimize(&request.pipeline, &request.blendpath, &request.blender)
+                                .map_err(|err| format!("{}", err));
+                if event_sender.send(OptimizeDone{ job_id: request.job_id, result }).is_err(){
+                    break;
+                }
+            }
+        });
+
+        Optimizer{
+            requests,
+            events,
+            in_flight: HashMap::new()
+        }
+    }
+
+    /// Queue a request for the background thread. Returns false if the \
+    /// thread doesn't run anymore
+    pub fn dispatch(&mut self, request: OptimizeRequest) -> bool{
+        let (job_id, blendpath) = (request.job_id.clone(), request.blendpath.clone());
+        if self.requests.send(request).is_ok(){
+            self.in_flight.insert(job_id, blendpath);
+            true
+        }else{
+            false
+        }
+    }
+
+    /// Return all results that arrived since the last call, without blocking
+    pub fn poll(&mut self) -> Vec<OptimizeDone>{
+        let done: Vec<OptimizeDone> = self.events.try_iter().collect();
+        for d in done.iter(){
+            self.in_flight.remove(&d.job_id);
+        }
+        done
+    }
+
+    /// Returns true if the blendfile of the given job is being optimized
+    pub fn is_optimizing(&self, job_id: &str) -> bool{
+        self.in_flight.contains_key(job_id)
+    }
+
+    /// Returns true if the blendfile at the given path is being optimized \
+    /// for any job
+    pub fn is_optimizing_file(&self, blendpath: &Path) -> bool{
+        self.in_flight.values().any(|path| path == blendpath)
+    }
+}
+
 
-static OPTIMIZE_PY: &'static str = include_str!("optimize.py");
 
 
 impl Work{
@@ -43,118 +137,146 @@ impl Work{
         }
     }
 
-    /// Filter all Blendfiles that are downloaded
-    pub fn optimize_blendfiles(&mut self){
+    /// Collect the results of the hook pipeline (see `work::hooks`), store \
+    /// them on the Blendfiles and publish their scene reports, then hand the \
+    /// Blendfiles that are downloaded to the `Optimizer`. Jobs whose pipeline \
+    /// failed or couldn't be set up are rejected (see `work::preflight`)
+    pub fn optimize_blendfiles<T>(&mut self, transport: &mut T) where T: Transport{
+        let mut optimized = false;
+        for done in self.optimizer.poll(){
+            // The job has been dropped (e.g. canceled) while it was optimized
+            match self.blendfiles.get(&done.job_id){
+                Some(&Blend::Downloaded(_)) => (),
+                _ => continue
+            }
+            let id = done.job_id;
+            match done.result{
+                Ok(results) => {
+                    println!("{}", format!(" ✔️ [WORKER][      ][{}]          Optimized blendfile ({} hook(s))", &id[..6], results.hooks.len()).green());
+                    if let Err(err) = self.cache.set_optimized(&id, results.clone()){
+                        errrun(format!("Couldn't mark cached blendfile for job [{}] as optimized: {}", &id[..6], err));
+                    }
+                    self.set_hook_results(&id, results, transport);
+                    optimized = true;
+                },
+                Err(err) => {
+                    errrun(format!("Couldn't optimize blendfile for job [{}]: {}", &id[..6], err));
+                    self.rejected_jobs.insert(id, (Failure::Hooks, err));
+                }
+            }
+        }
+
         if self.has_task() && !self.all_jobs_finished(){
+            let rejected_jobs = &self.rejected_jobs;
+            let optimizer = &self.optimizer;
+            let pending: Vec<(String, PathBuf)> = self.blendfiles.iter()
+                                                                 .filter(|(id, _)| !rejected_jobs.contains_key(id.as_str()))
+                                                                 .filter(|(id, _)| !optimizer.is_optimizing(id.as_str()))
+                                                                 .filter_map(|(id, blend)| match blend{
+                                                                     Blend::Downloaded(bf) => Some((id.clone(), bf.path.clone())),
+                                                                     _ => None
+                                                                 })
+                                                                 .collect();
+
+            if !pending.is_empty(){
+                match Pipeline::from_config(&self.config.hooks){
+                    Ok(pipeline) => optimized |= self.dispatch_optimizations(&pipeline, pending, transport),
+                    Err(err) => {
+                        // Without a pipeline no blendfile gets ready, so \
+                        // give the Tasks back to the other workers
+                        errrun(format!("Couldn't set up the hook pipeline: {}", err));
+                        for (id, _) in pending{
+                            self.rejected_jobs.insert(id, (Failure::Hooks, format!("Couldn't set up the hook pipeline: {}", err)));
+                        }
+                    }
+                }
+            }
+        }
 
-            // Generate a hashmap with optimized blends
-            let h: HashMap<String, Blend> = 
-            self.blendfiles.iter_mut()
-                           .filter(|(_, blend)|{
-                                match blend{
-                                    Blend::Downloaded(_) => true,
-                                    _ => false
-                                }
-                           })
-                           .map(|(id, blend)|{
-                                let path = blend.clone().unwrap().path;
-                                match optimize(path){
-                                    Ok(_) => {
-                                        println!("{}", format!(" ✔️ [WORKER][      ][{}]          Optimized blendfile", &id[..6]).green());
-                                        Some((id.clone(), Blend::Optimized(blend.clone().unwrap())))
-                                    },
-                                    Err(err) => {
-                                        errrun(format!("Couldn't optimize blendfile for job [{}]: {}", &id[..6], err));
-                                        None
-                                    }
-                                }
-                           })
-                           .filter(|a| a.is_some())
-                           .map(|a| a.unwrap())
-                           .collect();
-
-            // Extend
-            self.blendfiles.extend(h.into_iter());
+        // Forward the results with the Tasks
+        if optimized{
+            self.add_hook_results_to_tasks();
         }
     }
 
-}
 
+    /// Reuse the cached results of the current pipeline version or queue the \
+    /// blendfiles for the `Optimizer`. Returns true if results were reused
+    fn dispatch_optimizations<T>(&mut self, pipeline: &Pipeline, pending: Vec<(String, PathBuf)>, transport: &mut T) -> bool where T: Transport{
+        let version = pipeline.version();
+        let mut reused = false;
+        for (id, path) in pending{
+            // The same file might be used by multiple jobs, optimize it only once
+            let cached = match self.cache.hook_results(&id){
+                Some(results) if results.version == version => Some(results.clone()),
+                _ => None
+            };
+            match cached{
+                Some(results) => {
+                    self.set_hook_results(&id, results, transport);
+                    reused = true;
+                },
+                // Wait for the job that uses the same file, its results get reused
+                None if self.optimizer.is_optimizing_file(&path) => (),
+                None => {
+                    // Optimize with the Blender the job is going to be rendered with
+                    let blender = self.blender_for_job(&id).path;
+                    let request = OptimizeRequest{
+                        job_id: id.clone(),
+                        pipeline: pipeline.clone(),
+                        blendpath: path,
+                        blender
+                    };
+                    if !self.optimizer.dispatch(request){
+                        errrun(format!("Couldn't optimize blendfile for job [{}]: the optimizer doesn't run anymore", &id[..6]));
+                    }
+                }
+            }
+        }
+        reused
+    }
 
-/// Execute the jobs blendfile with optimize_blend.py, gather data and optimize settings.
-fn optimize(blendpath: PathBuf) -> GenResult<()>{
-    if Path::new(&blendpath).exists(){
-        // Run Blend with Python
-        match NamedTempFile::new(){
-            Ok(mut tempfile) => {
-                match io::copy(&mut OPTIMIZE_PY.as_bytes(), &mut tempfile){
-                    Ok(_) => {
-                        let path = tempfile.path();
-                        match run_with_python(&*blendpath.to_string_lossy(), &path.to_string_lossy()){
-                            Ok(_)    => Ok(()),
-                            Err(err) => Err(From::from(format!("Error while running with optimize.py: {}",  err)))
-                        }
 
-                    },
-                    Err(err) => Err(From::from(format!("Error: Failed to copy optimize.py to tempfile: {}", err)))
-                }
-            },
-            Err(err) => Err(From::from(format!("Error: Couldn't create tempfile: {}", err)))
+    /// Print and publish the scene report of the pipeline results and store \
+    /// them on the jobs Blendfile, which becomes `Blend::Optimized`
+    fn set_hook_results<T>(&mut self, id: &str, results: PipelineResult, transport: &mut T) where T: Transport{
+        if let Some(ref report) = results.report{
+            report.print(id);
+            report.publish(id, self.config.id, transport);
+        }
+        if let Some(blend) = self.blendfiles.remove(id){
+            let mut bf = blend.unwrap();
+            bf.hook_results = Some(results);
+            self.blendfiles.insert(id.to_string(), Blend::Optimized(bf));
         }
-    }else{
-        Err(From::from(format!("Didn't find blendfile at {}", blendpath.to_string_lossy())))
     }
+
 }
 
 
-/// Execute the checked blend-file at blend_path with the python file at python_path
-/// The final command will look something like this:
-/// ```text
-/// blender -b myfile.blend --disable-autoexec --python path/to/optimize_blend.py
-/// ```
-fn run_with_python<S>(path: S, pythonpath: S) -> GenResult<String>where S: Into<String>{
-    let path = path.into();
-    let pythonpath = pythonpath.into();
-
-    // Pass variables as environment variables, let blender run optimize_blend.py
-    // to set some things straight and save a new file
-    // blender -b / --disable-autoexec --python /usr/local/lib/optimize_blend.py
-
-    println!("blender -b {}", path);
-    let command = Command::new("blender")
-            .arg("-b")
-            .arg(&path)
-            .arg("--disable-autoexec")
-            .arg("--python")
-            .arg(pythonpath)
-            .output()?;
-
-    // Collect all lines starting with "{" for JSON
-    let output: String = String::from_utf8(command.stdout.clone())?
-        .lines()
-        // .inspect(|line| println!("{}", format!("INSPECTION: {}", line).bold().bright_blue()))
-        .collect();
-
-    // Error on empty string
-    if output == "" { 
-        Err(From::from(String::from_utf8(command.stdout).unwrap())) 
-    } else {
-        // Set permissions
-        match fs::metadata(&path){
-            Ok(meta) => {
-                // Set the permissions to 775
-                let mut permissions = meta.permissions();
-                
-                #[cfg(unix)]
-                permissions.set_mode(0o775);
-                
-                match fs::set_permissions(&path, permissions){
-                    Ok(_) => (),
-                    Err(err) => eprintln!("Error: failed to set permissions to 775: {}", err)
-                }
-            },
-            Err(err) => eprintln!("Error: Failed to get file metadata: {}", err)
-        }
-        Ok(output)
+/// Run the hook pipeline on the jobs blendfile using the given blender \
+/// executable and make the (maybe saved) file readable for the bender group
+fn optimize(pipeline: &Pipeline, blendpath: &Path, blender: &Path) -> GenResult<PipelineResult>{
+    if !blendpath.exists(){
+        return Err(From::from(format!("Didn't find blendfile at {}", blendpath.to_string_lossy())));
     }
-}
\ No newline at end of file
+    let results = pipeline.run(blender, blendpath)?;
+
+    // Set permissions
+    match fs::metadata(blendpath){
+        Ok(meta) => {
+            // Set the permissions to 775
+            let mut permissions = meta.permissions();
+            
+            #[cfg(unix)]
+            permissions.set_mode(0o775);
+            
+            match fs::set_permissions(blendpath, permissions){
+                Ok(_) => (),
+                Err(err) => eprintln!("Error: failed to set permissions to 775: {}", err)
+            }
+        },
+        Err(err) => eprintln!("Error: Failed to get file metadata: {}", err)
+    }
+    Ok(results)
+}
@@ -0,0 +1,67 @@
+//! The work::pause module handles jobs that have been paused in flaskbender \
+//! (see `work::jobstatus`). While a job is paused:
+//! - none of its Tasks are started, renders that are running already finish
+//! - its queued Tasks stay reserved on this worker, or are handed back to the \
+//!   broker if `hand_back_paused` is set (so the worker can take other work). \
+//!   Each Task is handed back only once: if it arrives here again, it stays \
+//!   reserved, so it doesn't bounce between the broker and the worker. The \
+//!   hand back doesn't count as a redelivery either
+//! - its blendfile stays where it is, so resuming the job doesn't need to \
+//!   download or optimize it again. Once all of its Tasks have been handed \
+//!   back, the worker lets go of it (it stays in the cache until evicted)
+
+use ::*;
+use bender_job::Task;
+use work::transport::Transport;
+use work::shutdown::hand_back;
+use work::jobstatus::JobState;
+use itertools::Itertools;
+
+
+
+
+impl Work{
+    /// Returns true if the job of the given Task is paused. A Task that has \
+    /// been handed back for its paused job counts as paused until a status \
+    /// poll says otherwise
+    pub fn is_paused(&self, task: &Task) -> bool{
+        match self.job_state(task.parent_id.as_str()){
+            JobState::Paused  => true,
+            JobState::Unknown => task.data.get("paused-hand-back").map(|h| h == "true").unwrap_or(false),
+            _                 => false
+        }
+    }
+
+
+    /// Hand the queued Tasks of paused jobs back to the broker, if the config \
+    /// says so and they weren't handed back before
+    pub fn hand_back_paused<T>(&mut self, transport: &mut T) where T: Transport{
+        if !self.config.hand_back_paused || !self.parent_jobs.values().any(|state| state.is_paused()){
+            return;
+        }
+        let tasks = std::mem::take(&mut self.tasks);
+        let (paused, rest): (Vec<Task>, Vec<Task>) = tasks.into_iter()
+                                                         .partition(|t| !t.is_ended() && self.is_paused(t) && !t.data.contains_key("paused-hand-back"));
+        self.tasks = rest;
+        for t in paused.iter(){
+            let redelivered = t.data.get("task-redelivered").map(|r| r == "true").unwrap_or(false);
+            self.paused_hand_backs.insert(t.id.clone(), redelivered);
+            hand_back(t, transport);
+        }
+
+        // Let go of the blendfiles of jobs we have no Tasks of anymore, the \
+        // files stay in the cache until they get evicted
+        let ids: Vec<String> = self.unique_parent_ids().map(|id| id.to_string()).collect();
+        let released: Vec<String> = paused.iter()
+                                          .map(|t| t.parent_id.clone())
+                                          .unique()
+                                          .filter(|id| !ids.contains(id))
+                                          .collect();
+        for id in released.iter(){
+            self.cache.release(id);
+            self.blendfiles.remove(id);
+            self.parent_jobs.remove(id);
+            okrun(format!("Released blendfile of paused job [{}], all of its Tasks have been handed back", &id[..6]));
+        }
+    }
+}
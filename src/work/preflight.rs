@@ -0,0 +1,174 @@
+//! The work::preflight module checks optimized blendfiles against the policy \
+//! of the worker before any of their Tasks is started. It uses the \
+//! `SceneReport` of the hook pipeline (see `work::report`) and rejects files \
+//! that:
+//! - point at missing external assets of the kinds in `reject_missing` (by \
+//!   default linked libraries)
+//! - use a render engine that isn't in `engines` (empty means any)
+//! - write their output to an absolute path outside of the `outpath` (unless \
+//!   `allow_output_outside_outpath` is set). Relative paths (starting with \
+//!   `//`) are fine
+//!
+//! Jobs whose file failed the hook pipeline (see `work::optimize`) are \
+//! rejected the same way, so the pipeline doesn't run again and again.
+//!
+//! If a file is rejected, all Tasks of its job are errored at once with the \
+//! same reason and their deliveries are given back (see `error_task`), \
+//! instead of failing them one by one in the render slots. The reasons are \
+//! specific to this worker, so another one might render the job. The job is \
+//! remembered in `rejected_jobs`, and its Tasks that arrive here again are \
+//! handed back right away. Files without a scene report are not checked.
+
+use ::*;
+use bender_job::Task;
+use blend::Blend;
+use work::report::{SceneReport, AssetKind};
+use work::retry::Failure;
+use work::transport::Transport;
+
+/// The render engines that come with Blender
+const BUILTIN_ENGINES: &[&str] = &["BLENDER_RENDER", "BLENDER_EEVEE", "BLENDER_EEVEE_NEXT", "BLENDER_WORKBENCH", "CYCLES"];
+
+
+
+/// The `preflight` table of the `WorkerConfig`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PreflightPolicy{
+    pub enabled: bool,
+    /// The render engines this worker renders, empty means any
+    pub engines: Vec<String>,
+    /// Reject files with missing assets of these kinds
+    pub reject_missing: Vec<AssetKind>,
+    /// Accept files whose absolute output path is outside of the `outpath`
+    pub allow_output_outside_outpath: bool
+}
+
+
+impl Default for PreflightPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl PreflightPolicy{
+    /// Accept the engines that come with Blender, reject missing libraries
+    pub fn new() -> Self{
+        PreflightPolicy{
+            enabled: true,
+            engines: BUILTIN_ENGINES.iter().map(|engine| engine.to_string()).collect(),
+            reject_missing: vec![AssetKind::Library],
+            allow_output_outside_outpath: false
+        }
+    }
+
+    /// Check the scene report against the policy. Returns all problems in a \
+    /// single reason if the file should be rejected
+    pub fn check(&self, report: &SceneReport, outpath: &Path) -> Result<(), String>{
+        let mut problems = Vec::new();
+
+        let missing: Vec<String> = report.missing_assets.iter()
+                                                        .filter(|asset| self.reject_missing.contains(&asset.kind))
+                                                        .map(|asset| format!("{:?} \"{}\" ({})", asset.kind, asset.name, asset.path))
+                                                        .collect();
+        if !missing.is_empty(){
+            problems.push(format!("missing {}", missing.join(", ")));
+        }
+
+        if !self.engines.is_empty() && !self.engines.iter().any(|engine| engine.eq_ignore_ascii_case(&report.render_engine)){
+            problems.push(format!("the render engine {} is not supported (supported: {})", report.render_engine, self.engines.join(", ")));
+        }
+
+        // Relative paths start with "//" and end up next to the blendfile
+        let output = Path::new(&report.output_path);
+        if !self.allow_output_outside_outpath && !report.output_path.starts_with("//") && output.is_absolute() && !output.starts_with(outpath){
+            problems.push(format!("the output path {} is outside of {}", report.output_path, outpath.to_string_lossy()));
+        }
+
+        if problems.is_empty(){
+            Ok(())
+        }else{
+            Err(problems.join("; "))
+        }
+    }
+}
+
+
+
+
+impl Work{
+    /// Returns true if the Tasks blendfile is optimized and passed the \
+    /// pre-flight validation
+    pub fn blendfile_is_ready(&self, t: &Task) -> bool{
+        match self.blendfiles.get(&t.parent_id){
+            Some(&Blend::Optimized(ref bf)) => bf.preflight_passed,
+            _ => false
+        }
+    }
+
+
+    /// Validate the optimized blendfiles that haven't been checked yet and \
+    /// reject the Tasks of jobs whose file failed
+    pub fn preflight_blendfiles<T>(&mut self, transport: &mut T) where T: Transport{
+        if !self.has_task(){
+            return;
+        }
+
+        // Forget about rejected jobs once we have no Tasks of them anymore
+        let ids: Vec<String> = self.unique_parent_ids().map(|id| id.to_string()).collect();
+        self.rejected_jobs.retain(|id, _| ids.contains(id));
+
+        for (id, blend) in self.blendfiles.iter_mut(){
+            if let Blend::Optimized(ref mut bf) = *blend{
+                if bf.preflight_passed || self.rejected_jobs.contains_key(id){
+                    continue;
+                }
+                let result = match bf.scene_report(){
+                    Some(report) if self.config.preflight.enabled => self.config.preflight.check(report, &self.config.outpath),
+                    _ => Ok(())
+                };
+                match result{
+                    Ok(()) => bf.preflight_passed = true,
+                    Err(reason) => {
+                        errrun(format!("The blendfile of job [{}] failed the pre-flight validation: {}", &id[..6], reason));
+                        self.rejected_jobs.insert(id.clone(), (Failure::Preflight, reason));
+                    }
+                }
+            }
+        }
+
+        let rejected: Vec<(String, Failure, String)> = self.rejected_jobs.iter()
+                                                                         .map(|(id, &(failure, ref reason))| (id.clone(), failure, reason.clone()))
+                                                                         .collect();
+        for (id, failure, reason) in rejected.into_iter(){
+            self.reject_job(&id, failure, &reason, transport);
+        }
+    }
+
+
+    /// Error all Tasks of the given job that haven't ended yet with the given \
+    /// failure and reason and give their deliveries back
+    fn reject_job<T>(&mut self, id: &str, failure: Failure, reason: &str, transport: &mut T) where T: Transport{
+        let tasks = std::mem::take(&mut self.tasks);
+        let (of_job, rest): (Vec<Task>, Vec<Task>) = tasks.into_iter()
+                                                          .partition(|t| t.parent_id == id && !t.is_ended());
+        self.tasks = rest;
+        if of_job.is_empty(){
+            return;
+        }
+
+        let (what, key) = match failure{
+            Failure::Hooks => ("couldn't be optimized", "hook-error"),
+            _              => ("failed the pre-flight validation", "preflight-error")
+        };
+        let h = format!("Worker [{}] rejected {} Task(s) of job [{}], its blendfile {}: {}", self.config.id, of_job.len(), id, what, reason);
+        self.add_history(h.as_str());
+        let err = format!("Blendfile {}: {}", what, reason);
+        for mut t in of_job{
+            t.add_data("last-failure", format!("{:?}", failure).as_str());
+            t.add_data(key, reason);
+            self.error_task(t, err.as_str(), transport);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+//! The work::progress module parses the status lines Blender prints while \
+//! rendering into a structured `Progress` record. A typical line looks like:
+//!
+//! ```text
+//! Fra:23 Mem:29.35M (0.00M, Peak 29.64M) | Time:00:00.78 | Remaining:00:00.13 | Mem:8.15M, Peak:8.29M | Scene, RenderLayer | Path Tracing Tile 7/40
+//! ```
+//!
+//! Depending on the Blender version and render engine the last part can also \
+//! read `Rendered 32/128 Tiles, Sample 10/128` or `Rendering 12 / 64 samples`.
+
+use ::*;
+use chrono::{Utc, DateTime};
+use work::transport::Transport;
+
+
+
+
+/// The progress of a single render, as far as Blender told us about it. Every \
+/// field is optional, because not every line contains every information
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Progress{
+    pub frame: Option<isize>,
+    pub tile: Option<(usize, usize)>,
+    pub sample: Option<(usize, usize)>,
+    pub elapsed_seconds: Option<f64>,
+    pub remaining_seconds: Option<f64>,
+    pub peak_memory_mb: Option<f64>,
+    pub status: Option<String>,
+    pub updated: Option<DateTime<Utc>>
+}
+
+
+impl Progress{
+    /// Create a new empty Progress
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Parse a single line of Blender output. Returns None if it isn't a \
+    /// status line (these start with `Fra:`)
+    pub fn parse<S>(line: S) -> Option<Self> where S: Into<String>{
+        let line = line.into();
+        let line = line.trim();
+        if !line.starts_with("Fra:"){
+            return None;
+        }
+
+        let mut progress = Self::new();
+        let mut segments = line.split(" | ");
+
+        // The first segment holds the frame and the memory usage:
+        // Fra:23 Mem:29.35M (0.00M, Peak 29.64M)
+        if let Some(first) = segments.next(){
+            progress.frame = first["Fra:".len()..].split_whitespace()
+                                                  .next()
+                                                  .and_then(|frame| frame.parse::<isize>().ok());
+            progress.peak_memory_mb = first.find("Peak ")
+                                           .and_then(|i| parse_memory(first[i+"Peak ".len()..].trim_end_matches(')')));
+        }
+
+        for segment in segments{
+            if segment.starts_with("Time:"){
+                progress.elapsed_seconds = parse_time(&segment["Time:".len()..]);
+            }else if segment.starts_with("Remaining:"){
+                progress.remaining_seconds = parse_time(&segment["Remaining:".len()..]);
+            }else if segment.starts_with("Mem:"){
+                // This is the memory of the render device, ignore it
+            }else{
+                let (tile, sample) = parse_fractions(segment);
+                progress.tile = tile.or(progress.tile);
+                progress.sample = sample.or(progress.sample);
+                progress.status = Some(segment.to_string());
+            }
+        }
+        progress.updated = Some(Utc::now());
+        Some(progress)
+    }
+
+    /// Update self with all information found in the given line and return \
+    /// true if there was any. Fields that are missing in the line (e.g. the \
+    /// remaining time) keep their last known value
+    pub fn update<S>(&mut self, line: S) -> bool where S: Into<String>{
+        match Self::parse(line){
+            Some(other) => {
+                // A new frame starts from scratch
+                if other.frame != self.frame{
+                    *self = Self::new();
+                }
+                self.frame             = other.frame.or(self.frame);
+                self.tile              = other.tile.or(self.tile);
+                self.sample            = other.sample.or(self.sample);
+                self.elapsed_seconds   = other.elapsed_seconds.or(self.elapsed_seconds);
+                self.remaining_seconds = other.remaining_seconds.or(self.remaining_seconds);
+                self.peak_memory_mb    = other.peak_memory_mb.or(self.peak_memory_mb);
+                self.status            = other.status.or_else(|| self.status.take());
+                self.updated           = other.updated;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Return the progress of the current frame as a factor between 0.0 and \
+    /// 1.0 (samples are preferred over tiles)
+    pub fn fraction(&self) -> Option<f64>{
+        self.sample.or(self.tile)
+                   .filter(|&(_, total)| total > 0)
+                   .map(|(done, total)| done as f64 / total as f64)
+    }
+}
+
+
+
+
+/// The message posted with the routing key `progress.<worker-id>`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProgressReport{
+    pub worker_id: Uuid,
+    pub task_id: String,
+    pub parent_id: String,
+    pub slot: usize,
+    pub progress: Progress
+}
+
+
+
+
+impl Work{
+    /// Post the progress of the Task in the given slot via the transport. \
+    /// This is rate limited by the `progress_rate_seconds` setting and only \
+    /// happens if Blender told us something new since the last post
+    pub fn post_progress<T>(&mut self, slot: usize, transport: &mut T) where T: Transport{
+        let worker_id = self.config.id;
+        let rate = chrono::Duration::seconds(self.config.progress_rate_seconds as i64);
+        let slot_index = slot;
+        let slot = &mut self.slots[slot];
+
+        let should_post = match (slot.last_progress_post, slot.progress.updated){
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(last), Some(updated)) => updated > last && Utc::now() - last > rate
+        };
+
+        if should_post{
+            if let Some(ref task) = slot.task{
+                let report = ProgressReport{
+                    worker_id,
+                    task_id: task.id.clone(),
+                    parent_id: task.parent_id.clone(),
+                    slot: slot_index,
+                    progress: slot.progress.clone()
+                };
+                match serde_json::to_vec(&report){
+                    Ok(json) => transport.post_event(format!("progress.{}", worker_id), json),
+                    Err(err) => eprintln!(" ✖ [WORKER][{}] Error: Failed to serialize progress: {}", &task.id[..6], err)
+                }
+                slot.last_progress_post = Some(Utc::now());
+            }
+        }
+    }
+}
+
+
+
+
+/// Parse a duration as printed by Blender (`MM:SS.ss` or `HH:MM:SS.ss`) into \
+/// seconds
+fn parse_time(s: &str) -> Option<f64>{
+    s.trim()
+     .split(':')
+     .map(|part| part.parse::<f64>().ok())
+     .fold(Some(0.0), |acc, part| match (acc, part){
+        (Some(acc), Some(part)) => Some(acc * 60.0 + part),
+        _ => None
+     })
+}
+
+
+/// Parse memory as printed by Blender (e.g. `29.64M` or `1.20G`) into megabytes
+fn parse_memory(s: &str) -> Option<f64>{
+    let s = s.trim();
+    if s.ends_with('M'){
+        s.trim_end_matches('M').parse::<f64>().ok()
+    }else if s.ends_with('G'){
+        s.trim_end_matches('G').parse::<f64>().ok().map(|g| g * 1024.0)
+    }else{
+        s.parse::<f64>().ok()
+    }
+}
+
+
+/// Find `done/total` pairs in a status segment and decide by the neighbouring \
+/// words whether they count tiles or samples. Returns (tile, sample)
+fn parse_fractions(segment: &str) -> (Option<(usize, usize)>, Option<(usize, usize)>){
+    let segment = segment.replace(" / ", "/");
+    let words: Vec<&str> = segment.split_whitespace().collect();
+    let mut tile = None;
+    let mut sample = None;
+
+    for (i, word) in words.iter().enumerate(){
+        let mut parts = word.trim_end_matches(',').split('/');
+        let fraction = match (parts.next(), parts.next(), parts.next()){
+            (Some(done), Some(total), None) => {
+                match (done.parse::<usize>(), total.parse::<usize>()){
+                    (Ok(done), Ok(total)) => (done, total),
+                    _ => continue
+                }
+            },
+            _ => continue
+        };
+
+        // Look at the word before first, then at the one after
+        let before = if i > 0 { words.get(i-1) } else { None };
+        let after = words.get(i+1);
+        let kind = [before, after].iter()
+                                  .filter_map(|word| word.map(|w| w.to_lowercase()))
+                                  .find(|w| w.starts_with("tile") || w.starts_with("sample"));
+        match kind{
+            Some(ref w) if w.starts_with("tile") => tile = Some(fraction),
+            Some(_) => sample = Some(fraction),
+            None => ()
+        }
+    }
+    (tile, sample)
+}
+
+
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// The output of a Cycles render of a single frame
+    const EXAMPLE_STDOUT: &str = include_str!("../../resources/blender_example_stdout.txt");
+
+    #[test]
+    fn parse_reads_a_cycles_status_line(){
+        let line = "Fra:23 Mem:29.35M (0.00M, Peak 29.64M) | Time:00:00.78 | Remaining:00:00.13 | Mem:8.15M, Peak:8.29M | Scene, RenderLayer | Path Tracing Tile 7/40";
+        let progress = Progress::parse(line).unwrap();
+        assert_eq!(progress.frame, Some(23));
+        assert_eq!(progress.peak_memory_mb, Some(29.64));
+        assert_eq!(progress.elapsed_seconds, Some(0.78));
+        assert_eq!(progress.remaining_seconds, Some(0.13));
+        assert_eq!(progress.tile, Some((7, 40)));
+        assert_eq!(progress.sample, None);
+        assert_eq!(progress.status, Some("Path Tracing Tile 7/40".to_string()));
+        assert!(progress.updated.is_some());
+    }
+
+    #[test]
+    fn update_follows_the_example_render(){
+        let lines: Vec<&str> = EXAMPLE_STDOUT.lines().collect();
+        assert_eq!(lines.iter().filter_map(|line| Progress::parse(*line)).count(),
+                   lines.iter().filter(|line| line.starts_with("Fra:")).count());
+
+        let mut progress = Progress::new();
+        let mut fractions = Vec::new();
+        for line in lines.iter(){
+            if progress.update(*line){
+                fractions.push(progress.fraction());
+            }
+        }
+        assert_eq!(progress.frame, Some(23));
+        assert_eq!(progress.peak_memory_mb, Some(29.64));
+        assert_eq!(progress.elapsed_seconds, Some(1.42));
+        // The last lines don't tell the remaining time anymore
+        assert_eq!(progress.remaining_seconds, Some(0.01));
+        assert_eq!(progress.tile, Some((40, 40)));
+        assert_eq!(progress.sample, Some((4, 4)));
+        assert_eq!(progress.status, Some("Sce: Scene Ve:0 Fa:0 La:0".to_string()));
+
+        // The progress never goes backwards once the tiles are counted
+        let known: Vec<f64> = fractions.into_iter().filter_map(|f| f).collect();
+        assert!(!known.is_empty());
+        assert!(known.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(known.last(), Some(&1.0));
+    }
+
+    #[test]
+    fn parse_fractions_tells_tiles_from_samples(){
+        // Cycles in Blender 2.8
+        assert_eq!(parse_fractions("Rendered 32/128 Tiles, Sample 10/128"), (Some((32, 128)), Some((10, 128))));
+        // EEVEE
+        assert_eq!(parse_fractions("Rendering 12 / 64 samples"), (None, Some((12, 64))));
+        // Cycles in Blender 2.7
+        assert_eq!(parse_fractions("Path Tracing Tile 39/40, Sample 4/4"), (Some((39, 40)), Some((4, 4))));
+    }
+
+    #[test]
+    fn parse_fractions_skips_malformed_pairs(){
+        assert_eq!(parse_fractions("Path Tracing Tile"), (None, None));
+        assert_eq!(parse_fractions("Tile 1/2/3"), (None, None));
+        assert_eq!(parse_fractions("Tile a/40, Sample 4/b"), (None, None));
+        assert_eq!(parse_fractions("Tile -1/40"), (None, None));
+        // A fraction without a word saying what it counts
+        assert_eq!(parse_fractions("Synchronizing 3/7 objects"), (None, None));
+    }
+
+    #[test]
+    fn parse_handles_malformed_lines(){
+        assert_eq!(Progress::parse(""), None);
+        assert_eq!(Progress::parse("Saved: '/tmp/000023.png'"), None);
+        assert_eq!(Progress::parse(" Time: 00:01.52 (Saving: 00:00.09)"), None);
+
+        // Still a status line, but without anything we understand
+        let progress = Progress::parse("Fra: | Time:soon | Remaining:00:xx").unwrap();
+        assert_eq!(progress.frame, None);
+        assert_eq!(progress.peak_memory_mb, None);
+        assert_eq!(progress.elapsed_seconds, None);
+        assert_eq!(progress.remaining_seconds, None);
+        assert_eq!(progress.fraction(), None);
+
+        let progress = Progress::parse("  Fra:-1 Mem:1.00G (0.00M, Peak 1.50G) | Time:01:02:03.50 | Tile 0/0").unwrap();
+        assert_eq!(progress.frame, Some(-1));
+        assert_eq!(progress.peak_memory_mb, Some(1536.0));
+        assert_eq!(progress.elapsed_seconds, Some(3723.5));
+        assert_eq!(progress.tile, Some((0, 0)));
+        // Zero tiles don't make a fraction
+        assert_eq!(progress.fraction(), None);
+    }
+}
@@ -0,0 +1,119 @@
+//! The work::render module wraps spawned commands into a `Render`. The stdout \
+//! and stderr of the child process are read on background threads and streamed \
+//! into a channel, so checking on a running render never blocks the update loop \
+//! (which keeps beating its heart and uploading frames meanwhile).
+
+use ::*;
+use std::io;
+use std::io::{Read, BufRead, BufReader};
+use std::process::Child;
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+use chrono::{Utc, DateTime};
+
+
+
+
+/// A single line of output produced by a spawned command
+#[derive(Debug, Clone)]
+pub enum Output{
+    Stdout(String),
+    Stderr(String)
+}
+
+
+impl Output{
+    /// Return the line without caring where it came from
+    pub fn line(&self) -> &str{
+        match self{
+            Output::Stdout(line) => line.as_str(),
+            Output::Stderr(line) => line.as_str()
+        }
+    }
+}
+
+
+
+
+/// A Render holds a spawned child process together with the receiving end of \
+/// its output and the time the last line has been received
+#[derive(Debug)]
+pub struct Render{
+    child: Child,
+    receiver: Receiver<Output>,
+    readers: Vec<JoinHandle<()>>,
+    pub last_output: DateTime<Utc>
+}
+
+
+impl Render{
+    /// Take the piped stdout and stderr of the child and start reading them \
+    /// in the background
+    pub fn new(mut child: Child) -> Self{
+        let (sender, receiver) = mpsc::channel();
+        let mut readers = Vec::new();
+        if let Some(stdout) = child.stdout.take(){
+            readers.push(spawn_reader(stdout, sender.clone(), Output::Stdout));
+        }
+        if let Some(stderr) = child.stderr.take(){
+            readers.push(spawn_reader(stderr, sender, Output::Stderr));
+        }
+        Render{
+            child,
+            receiver,
+            readers,
+            last_output: Utc::now()
+        }
+    }
+
+    /// Returns the OS-assigned process identifier of the child
+    pub fn id(&self) -> u32{
+        self.child.id()
+    }
+
+    /// Return all lines that have been received since the last call. This \
+    /// never blocks
+    pub fn drain(&mut self) -> Vec<Output>{
+        let lines: Vec<Output> = self.receiver.try_iter().collect();
+        if !lines.is_empty(){
+            self.last_output = Utc::now();
+        }
+        lines
+    }
+
+    /// Check whether the child has exited without blocking
+    pub fn try_wait(&mut self) -> io::Result<Option<std::process::ExitStatus>>{
+        self.child.try_wait()
+    }
+
+    /// Wait for the background readers to reach the end of the output and \
+    /// return the remaining lines. Only call this once the child has exited
+    pub fn finish(&mut self) -> Vec<Output>{
+        for reader in self.readers.drain(..){
+            let _ = reader.join();
+        }
+        self.drain()
+    }
+
+    /// Kill the child and reap it
+    pub fn kill(&mut self) -> io::Result<()>{
+        self.child.kill()?;
+        self.child.wait()?;
+        Ok(())
+    }
+}
+
+
+/// Read the given pipe line by line on a background thread and send every \
+/// line wrapped by `wrap` into the channel until the pipe is closed
+fn spawn_reader<R, F>(pipe: R, sender: Sender<Output>, wrap: F) -> JoinHandle<()>
+where R: Read + Send + 'static, F: Fn(String) -> Output + Send + 'static{
+    thread::spawn(move ||{
+        BufReader::new(pipe).lines()
+                            .filter_map(|line| line.ok())
+                            .for_each(|line|{
+                                // The receiving end is gone if the render was dropped
+                                let _ = sender.send(wrap(line));
+                            });
+    })
+}
@@ -0,0 +1,104 @@
+//! The work::report module holds the `SceneReport`, which the built-in \
+//! `scene-info` hook (see `work::hooks`) writes while a blendfile is optimized: \
+//! the render engine, the output format and path, the resolution, the samples, \
+//! the external assets that are missing and the resources packed into the file.
+//!
+//! The report is stored with the hook results on the `Blendfile` and posted as \
+//! `scene.<worker-id>` once per job, so problems like missing textures show up \
+//! before any frame is rendered.
+
+use ::*;
+use work::transport::EventSink;
+
+
+
+
+/// The kinds of external assets a blendfile can point at
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AssetKind{
+    Texture,
+    Library,
+    Cache,
+    Sound,
+    Font,
+    MovieClip,
+    #[serde(other)]
+    Other
+}
+
+
+/// An external asset whose file doesn't exist on this worker
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MissingAsset{
+    pub kind: AssetKind,
+    /// The name of the datablock (or the object, for simulation caches)
+    pub name: String,
+    /// The absolute path that was checked
+    pub path: String
+}
+
+
+/// What Blender knows about the scene of a blendfile
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SceneReport{
+    pub scene: String,
+    pub render_engine: String,
+    /// e.g. `PNG` or `OPEN_EXR`
+    pub output_format: String,
+    /// The output path as set in the file (relative paths start with `//`)
+    pub output_path: String,
+    pub resolution: Option<(u32, u32)>,
+    pub frame_range: Option<(i64, i64)>,
+    pub samples: Option<u32>,
+    pub missing_assets: Vec<MissingAsset>,
+    /// The names of the datablocks packed into the file
+    pub packed_files: Vec<String>
+}
+
+
+impl SceneReport{
+    /// Returns true if the file contains packed resources
+    pub fn has_packed_resources(&self) -> bool{
+        !self.packed_files.is_empty()
+    }
+
+    /// Returns true if the file points at assets that don't exist
+    pub fn has_missing_assets(&self) -> bool{
+        !self.missing_assets.is_empty()
+    }
+
+    /// Print the report of the given job, warn about missing assets
+    pub fn print(&self, job_id: &str){
+        println!(" ✔️ [WORKER][      ][{}]          Scene \"{}\": {}, {} to {}, {} samples, {} packed file(s)",
+            &job_id[..6],
+            self.scene,
+            self.render_engine,
+            self.output_format,
+            if self.output_path.is_empty() { "<no output path>" } else { self.output_path.as_str() },
+            self.samples.map(|samples| samples.to_string()).unwrap_or_else(|| "?".to_string()),
+            self.packed_files.len());
+        for asset in self.missing_assets.iter(){
+            errrun(format!("[{}] Missing {:?} \"{}\" at {}", &job_id[..6], asset.kind, asset.name, asset.path));
+        }
+    }
+
+    /// Post the report of the given job as `scene.<worker-id>`
+    pub fn publish<E>(&self, job_id: &str, worker_id: Uuid, events: &mut E) where E: EventSink{
+        let event = ReportEvent{ job_id, worker_id, report: self };
+        match serde_json::to_vec(&event){
+            Ok(json) => events.post_event(format!("scene.{}", worker_id), json),
+            Err(err) => errrun(format!("[{}] Couldn't serialize the scene report: {}", &job_id[..6], err))
+        }
+    }
+}
+
+
+/// The body of the `scene.<worker-id>` event
+#[derive(Serialize, Debug)]
+struct ReportEvent<'a>{
+    job_id: &'a str,
+    worker_id: Uuid,
+    report: &'a SceneReport
+}
@@ -3,14 +3,27 @@
 
 
 use ::*;
-use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::time::Instant;
+use std::fs::{File, OpenOptions};
 use std::collections::HashMap;
-use reqwest::header::{USER_AGENT, CONTENT_TYPE};
+use reqwest::{Method, StatusCode};
+use reqwest::header::{CONTENT_TYPE, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use sha2::{Sha256, Digest};
+use hmac::{Hmac, Mac};
+use bender_job::Status;
+use work::downloads::{DownloadRequest, DownloadProgress, Fetched};
 
 
 pub type GenError = Box<std::error::Error>;
 pub type GenResult<T> = Result<T, GenError>;
 
+/// Header in which flaskbender announces the size of a blendfile in bytes
+const BLEND_SIZE_HEADER: &str = "x-blend-size";
+/// Header in which flaskbender announces the hex encoded SHA-256 of a blendfile
+const BLEND_HASH_HEADER: &str = "x-blend-sha256";
+
 
 
 
@@ -19,32 +32,23 @@ impl Work {
 
 
 
-    /// Request a single blendfile for a given Job-ID from flaskbender via http \
-    /// get request. Uses the User-Agent http header in combination with a json \
-    /// body to get the actual blendfile
-    pub fn request_blendfile<S>(&mut self, id: S) -> GenResult<PathBuf> where S: Into<String>{
+    /// Describe the request for the blendfile of the given Job-ID, so it can be \
+    /// run by `fetch_blendfile()` on a background thread (see `work::downloads`)
+    pub fn blendfile_request<S>(&self, id: S) -> DownloadRequest where S: Into<String>{
         let id = id.into();
         // Create the URL
         let url = self.config.bender_url.clone();
         let url = format!("{url}/job/worker/blend/{id}", url=url, id=id);
-        // Construct a file path
+        // Construct a file path for the download
         let mut savepath = self.config.blendpath.clone();
         savepath.push(format!("{id}.blend", id=id));
-        // Create the output file
-        let mut output_file = File::create(&*savepath)?;
-        // Build the Client
-        let client = reqwest::Client::new();
-        // Construct the json message body
-        let mut map = HashMap::new();
-        map.insert("request", "blendfile");
-        // Make the Request
-        client.get(url.as_str())
-              .header(CONTENT_TYPE, "application/json")
-              .header(USER_AGENT, "bender-worker")
-              .json(&map)
-              .send()?
-              .copy_to(&mut output_file)?;
-        Ok(savepath)
+        DownloadRequest{
+            job_id: id,
+            url,
+            client: self.client.clone(),
+            partpath: part_path(&savepath),
+            known_hashes: self.cache.hashes()
+        }
     }
 
 
@@ -52,23 +56,286 @@ impl Work {
 
 
 
-    pub fn request_jobstatus<S>(&self, id: S) -> GenResult<String> where S: Into<String>{
+    /// Request the status of the Job with the given ID from flaskbender. The \
+    /// response is the JSON serialized `bender_job::Status` of the Job
+    pub fn request_jobstatus<S>(&self, id: S) -> GenResult<Status> where S: Into<String>{
         let id = id.into();
         // Make a request to the URL
         let url = format!("{url}/job/worker/status/{id}", 
             url=self.config.bender_url.clone(), 
             id=id.clone());
 
-        let client = reqwest::Client::new();
-        let res = client.get(url.as_str())
-            .header(USER_AGENT, "bender-worker")
-            .send()?
-            .text()?;
-            
-        Ok(res)
+        let mut response = self.client.get(url.as_str())
+            .send()?;
+
+        if !response.status().is_success(){
+            let text = response.text().unwrap_or_else(|_| "Couldn't descramble response".to_string());
+            return Err(From::from(format!("Server responded with {}: {}", response.status(), text.trim())));
+        }
+        match response.json::<Status>(){
+            Ok(status) => Ok(status),
+            Err(err) => Err(From::from(format!("Malformed job status: {}", err)))
+        }
     }
 
 
     
 
-}
\ No newline at end of file
+}
+
+
+
+
+/// Request a single blendfile from flaskbender via http get request. Uses the \
+/// headers of the `BenderClient` in combination with a json body to get the actual \
+/// blendfile. The given closure is called with the progress every now and then.
+///
+/// The file is downloaded to `<id>.blend.part` first. If a previous download \
+/// broke off, it is resumed from where it stopped using a HTTP Range request. \
+/// Only once the file is complete (and matches the size and SHA-256 hash the \
+/// server announced) it is handed back for the blendfile cache. If the server \
+/// announces the hash of a file that is in the cache already, the download is \
+/// skipped altogether
+pub fn fetch_blendfile<F>(request: &DownloadRequest, mut on_progress: F) -> GenResult<Fetched> where F: FnMut(DownloadProgress){
+    let id = &request.job_id;
+    let partpath = &request.partpath;
+    // Resume from the end of a previous, incomplete download
+    let offset = fs::metadata(partpath).map(|m| m.len()).unwrap_or(0);
+    // Construct the json message body
+    let mut map = HashMap::new();
+    map.insert("request", "blendfile");
+    // Make the Request
+    let mut builder = request.client.request_with_body(Method::GET, request.url.as_str(), serde_json::to_vec(&map)?)
+                                    .header(CONTENT_TYPE, "application/json");
+    if offset > 0{
+        builder = builder.header(RANGE, format!("bytes={}-", offset));
+    }
+    let mut response = builder.send()?;
+
+    let status = response.status();
+    let expected_hash = header_string(&response, BLEND_HASH_HEADER).map(|hash| hash.to_lowercase());
+    let expected_size = header_string(&response, BLEND_SIZE_HEADER)
+                            .and_then(|size| size.parse::<u64>().ok())
+                            .or_else(|| total_size(&response));
+
+    // A file we know already doesn't need to be downloaded again
+    if let Some(ref hash) = expected_hash{
+        if status.is_success() && request.known_hashes.contains(hash){
+            let _ = fs::remove_file(partpath);
+            return Ok(Fetched::Cached{ hash: hash.clone() });
+        }
+    }
+
+    if status == StatusCode::RANGE_NOT_SATISFIABLE{
+        // There is nothing left to download, the part is (hopefully) complete
+    }else if status == StatusCode::PARTIAL_CONTENT{
+        // Only append if the server continues exactly where the part ends
+        let range = header_string(&response, CONTENT_RANGE.as_str()).unwrap_or_default();
+        match content_range_start(&range){
+            Some(0) => {
+                let mut output_file = File::create(partpath)?;
+                copy_with_progress(&mut response, &mut output_file, 0, expected_size, &mut on_progress)?;
+            },
+            Some(start) if start == offset => {
+                let mut output_file = OpenOptions::new().append(true).open(partpath)?;
+                copy_with_progress(&mut response, &mut output_file, offset, expected_size, &mut on_progress)?;
+            },
+            _ => {
+                // Start over with the next request
+                File::create(partpath)?;
+                return Err(From::from(format!("Request for blendfile of job [{}] returned the range \"{}\" instead of one starting at byte {}, restarting the download",
+                    &id[..6], range, offset)));
+            }
+        }
+    }else if status.is_success(){
+        // The server ignored the Range header (or there was none), start over
+        let mut output_file = File::create(partpath)?;
+        copy_with_progress(&mut response, &mut output_file, 0, expected_size, &mut on_progress)?;
+    }else{
+        return Err(From::from(format!("Request for blendfile of job [{}] failed with status {}", &id[..6], status)));
+    }
+
+    if let Err(err) = verify_download(partpath, expected_size, expected_hash.clone()){
+        // Don't resume from a broken file
+        fs::remove_file(partpath)?;
+        return Err(From::from(format!("Downloaded blendfile of job [{}] was discarded: {}", &id[..6], err)));
+    }
+    // Files are stored by their hash, calculate it if the server didn't tell
+    let hash = match expected_hash{
+        Some(hash) => hash,
+        None => sha256_hex(partpath)?
+    };
+    Ok(Fetched::Downloaded{ part: partpath.clone(), hash })
+}
+
+
+/// Copy everything from the reader to the writer, calling the closure with \
+/// the progress at most twice a second. `offset` is the number of bytes that \
+/// have been downloaded before
+fn copy_with_progress<R, W, F>(reader: &mut R, writer: &mut W, offset: u64, total: Option<u64>, on_progress: &mut F) -> io::Result<u64>
+    where R: Read, W: Write, F: FnMut(DownloadProgress)
+{
+    let mut buffer = [0u8; 64 * 1024];
+    let mut bytes = offset;
+    let mut last_report = Instant::now();
+    loop{
+        let n = match reader.read(&mut buffer){
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err)
+        };
+        writer.write_all(&buffer[..n])?;
+        bytes += n as u64;
+        if last_report.elapsed() > std::time::Duration::from_millis(500){
+            on_progress(DownloadProgress{ bytes, total });
+            last_report = Instant::now();
+        }
+    }
+    writer.flush()?;
+    on_progress(DownloadProgress{ bytes, total });
+    Ok(bytes - offset)
+}
+
+
+
+
+/// Return the path of the temporary file a download is written to
+pub fn part_path(path: &Path) -> PathBuf{
+    let mut part = path.as_os_str().to_os_string();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+
+/// Return the value of the header with the given name as a String
+fn header_string(response: &reqwest::Response, name: &str) -> Option<String>{
+    response.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string())
+}
+
+
+/// Return the size of the complete file, either from the total in the \
+/// Content-Range header (`bytes 100-199/200`) of a partial response or from \
+/// the Content-Length of a full one
+fn total_size(response: &reqwest::Response) -> Option<u64>{
+    let status = response.status();
+    if status == StatusCode::PARTIAL_CONTENT || status == StatusCode::RANGE_NOT_SATISFIABLE{
+        header_string(response, CONTENT_RANGE.as_str())
+            .and_then(|range| range.rsplit('/').next().and_then(|total| total.parse::<u64>().ok()))
+    }else{
+        header_string(response, CONTENT_LENGTH.as_str())
+            .and_then(|length| length.parse::<u64>().ok())
+    }
+}
+
+
+/// Return the first byte of a Content-Range header (`bytes 100-199/200`)
+fn content_range_start(range: &str) -> Option<u64>{
+    let range = range.trim();
+    if !range.starts_with("bytes "){
+        return None;
+    }
+    range["bytes ".len()..].trim_start()
+                           .split('-')
+                           .next()
+                           .and_then(|start| start.trim().parse::<u64>().ok())
+}
+
+
+/// Check the file at the given path against the expected size and hex encoded \
+/// SHA-256 hash (if they are known)
+pub fn verify_download<P>(path: P, expected_size: Option<u64>, expected_hash: Option<String>) -> GenResult<()> where P: AsRef<Path>{
+    let path = path.as_ref();
+    let size = fs::metadata(path)?.len();
+    if size == 0{
+        return Err(From::from("the file is empty"));
+    }
+    if let Some(expected_size) = expected_size{
+        if size != expected_size{
+            return Err(From::from(format!("expected {} bytes, got {}", expected_size, size)));
+        }
+    }
+    if let Some(expected_hash) = expected_hash{
+        let hash = sha256_hex(path)?;
+        if !hash.eq_ignore_ascii_case(&expected_hash){
+            return Err(From::from(format!("expected SHA-256 {}, got {}", expected_hash, hash)));
+        }
+    }
+    Ok(())
+}
+
+
+/// Return the hex encoded SHA-256 hash of the file at the given path
+pub fn sha256_hex<P>(path: P) -> GenResult<String> where P: AsRef<Path>{
+    let mut file = File::open(path.as_ref())?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop{
+        let n = file.read(&mut buffer)?;
+        if n == 0{
+            break;
+        }
+        hasher.input(&buffer[..n]);
+    }
+    Ok(hasher.result()
+             .iter()
+             .map(|byte| format!("{:02x}", byte))
+             .collect())
+}
+
+
+/// HMAC-SHA256 of data with the given key
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8>{
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length");
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+
+/// Lowercase hex representation of the given bytes
+pub fn to_hex(bytes: &[u8]) -> String{
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn hmac_sha256_matches_rfc_4231(){
+        // Test cases 1-4, 6 and 7 (5 is truncated)
+        let cases: Vec<(Vec<u8>, Vec<u8>, &str)> = vec![
+            (vec![0x0b; 20], b"Hi There".to_vec(),
+             "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+            (b"Jefe".to_vec(), b"what do ya want for nothing?".to_vec(),
+             "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+            (vec![0xaa; 20], vec![0xdd; 50],
+             "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"),
+            ((1..26).collect(), vec![0xcd; 50],
+             "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b"),
+            (vec![0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+             "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"),
+            (vec![0xaa; 131], b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to be hashed before being used by the HMAC algorithm.".to_vec(),
+             "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2")
+        ];
+        for (key, data, expected) in cases{
+            assert_eq!(to_hex(&hmac_sha256(&key, &data)), expected);
+        }
+    }
+
+    #[test]
+    fn content_range_start_reads_the_first_byte(){
+        assert_eq!(content_range_start("bytes 100-199/200"), Some(100));
+        assert_eq!(content_range_start("bytes 0-199/*"), Some(0));
+        assert_eq!(content_range_start(" bytes  42-99/100 "), Some(42));
+        assert_eq!(content_range_start("bytes */200"), None);
+        assert_eq!(content_range_start("items 100-199/200"), None);
+        assert_eq!(content_range_start(""), None);
+    }
+}
@@ -0,0 +1,106 @@
+//! The work::retry module decides what happens to a Task whose render failed. \
+//! Depending on the class of the failure and the `RetryPolicy` in the config \
+//! the Task is either queued again on this worker (after a backoff period) or \
+//! errored and its delivery handed back to the broker, which can then give it \
+//! to a different worker.
+//!
+//! The retry state lives in the Tasks data:
+//! - `retry-attempts`: the number of failed attempts so far
+//! - `retry-after`: the earliest time (RFC 3339) the Task may be started again
+//! - `last-failure`: the class of the last failure
+
+use ::*;
+use bender_job::Task;
+use chrono::{Utc, DateTime, Duration};
+
+
+
+
+/// The classes of failures a render can run into
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Failure{
+    /// The command couldn't be constructed or spawned
+    Spawn,
+    /// The command returned with a non-zero exit status
+    ExitStatus,
+    /// The render exceeded the `render_timeout_minutes`
+    Timeout,
+    /// The render didn't print anything for `stall_timeout_minutes`
+    Stall,
+    /// The blendfile for the Task wasn't there
+    MissingBlendfile,
+    /// The blendfile failed the pre-flight validation (see `work::preflight`)
+    Preflight,
+    /// A hook failed on the blendfile (see `work::hooks`)
+    Hooks,
+    /// Everything else (e.g. waiting for the command failed)
+    Other
+}
+
+
+
+
+/// Holds the retry policy for failed Tasks
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetryPolicy{
+    /// How often a Task is attempted on this worker in total
+    pub max_attempts: usize,
+    /// Seconds to wait before the first retry, doubled for each further one
+    pub backoff_seconds: u64,
+    /// The classes of failures that are worth another attempt
+    pub retryable: Vec<Failure>,
+    /// Whether the delivery of a finally failed Task should be requeued (so \
+    /// another worker can try) instead of rejected. A Task that already has \
+    /// been redelivered is always rejected to avoid endless ping-pong
+    pub requeue: bool
+}
+
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetryPolicy{
+    /// Create a new RetryPolicy with default values
+    pub fn new() -> Self{
+        RetryPolicy{
+            max_attempts:    3,
+            backoff_seconds: 30,
+            retryable:       vec![Failure::Spawn, Failure::ExitStatus, Failure::Timeout, Failure::Stall],
+            requeue:         true
+        }
+    }
+
+    /// Returns true if a Task that failed with the given failure on its nth \
+    /// attempt should be attempted again
+    pub fn should_retry(&self, failure: Failure, attempt: usize) -> bool{
+        attempt < self.max_attempts && self.retryable.contains(&failure)
+    }
+
+    /// Returns the backoff period after the nth failed attempt
+    pub fn backoff(&self, attempt: usize) -> Duration{
+        let exponent = std::cmp::min(attempt.saturating_sub(1), 16) as u32;
+        Duration::seconds((self.backoff_seconds * 2u64.pow(exponent)) as i64)
+    }
+}
+
+
+
+
+/// Return the number of failed attempts stored in the Tasks data
+pub fn attempts(task: &Task) -> usize{
+    task.data.get("retry-attempts")
+             .and_then(|a| a.parse::<usize>().ok())
+             .unwrap_or(0)
+}
+
+
+/// Returns true if the Task has no `retry-after` in the future
+pub fn is_due(task: &Task) -> bool{
+    match task.data.get("retry-after").and_then(|after| DateTime::parse_from_rfc3339(after).ok()){
+        Some(after) => Utc::now() >= after.with_timezone(&Utc),
+        None => true
+    }
+}
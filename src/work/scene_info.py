@@ -0,0 +1,76 @@
+# Built-in hook "scene-info": this script is meant to be run from within blender.
+# It reports the render settings of the scene and inspects it for problems
+# (missing external assets, packed resources). It changes nothing
+import bpy
+import json
+import os
+
+
+scene = bpy.context.scene
+render = scene.render
+
+result = {
+    "render_engine": render.engine,
+    # The size of the frames that actually get written
+    "resolution": [render.resolution_x * render.resolution_percentage // 100,
+                   render.resolution_y * render.resolution_percentage // 100],
+    "frame_range": [scene.frame_start, scene.frame_end]
+}
+
+if render.engine == 'CYCLES':
+    result["samples"] = scene.cycles.samples
+elif hasattr(scene, "eevee") and render.engine.startswith('BLENDER_EEVEE'):
+    result["samples"] = scene.eevee.taa_render_samples
+
+
+
+missing = []
+packed = []
+
+def check(kind, datablock, path):
+    """Remember the datablock if the file it points at doesn't exist"""
+    if not path or path == "<builtin>":
+        return
+    absolute = bpy.path.abspath(path, library=getattr(datablock, "library", None))
+    # Image sequences point at one of many files, check the directory
+    if getattr(datablock, "source", None) == 'SEQUENCE':
+        absolute = os.path.dirname(absolute)
+    if not os.path.exists(absolute):
+        missing.append({"kind": kind, "name": datablock.name, "path": absolute})
+
+for kind, datablocks in (("texture", bpy.data.images),
+                         ("library", bpy.data.libraries),
+                         ("sound", bpy.data.sounds),
+                         ("font", bpy.data.fonts),
+                         ("movieclip", bpy.data.movieclips)):
+    for datablock in datablocks:
+        if getattr(datablock, "packed_file", None) is not None:
+            packed.append(datablock.name)
+        elif kind != "texture" or datablock.source in {'FILE', 'SEQUENCE', 'MOVIE'}:
+            check(kind, datablock, datablock.filepath)
+
+# Alembic and USD caches (2.80+)
+for cache in getattr(bpy.data, "cache_files", []):
+    check("cache", cache, cache.filepath)
+
+# Fluid simulations baked to disk (2.82+)
+for obj in bpy.data.objects:
+    for modifier in obj.modifiers:
+        if modifier.type == 'FLUID' and modifier.fluid_type == 'DOMAIN':
+            directory = modifier.domain_settings.cache_directory
+            if not os.path.isdir(bpy.path.abspath(directory)):
+                missing.append({"kind": "cache", "name": obj.name, "path": bpy.path.abspath(directory)})
+
+result["report"] = {
+    "scene": scene.name,
+    "render_engine": render.engine,
+    "output_format": render.image_settings.file_format,
+    "output_path": render.filepath,
+    "resolution": result["resolution"],
+    "frame_range": result["frame_range"],
+    "samples": result.get("samples"),
+    "missing_assets": missing,
+    "packed_files": packed
+}
+
+print("BENDER-HOOK-RESULT:" + json.dumps(result))
@@ -0,0 +1,153 @@
+//! The work::shutdown module implements the graceful shutdown of the worker. \
+//! Once a SIGTERM or SIGINT has been received `Work::shutdown()` runs instead \
+//! of `Work::update()`:
+//! 1. No new Tasks are taken or started
+//! 2. All Tasks that haven't been started yet are handed back to the broker
+//! 3. Depending on the `shutdown_mode` running renders are either drained \
+//!    (waited for, their frames uploaded) or killed and handed back as well
+//! 4. A `shutdown.<worker-id>` event is posted and the worker can exit. Tasks \
+//!    whose frames couldn't be uploaded yet stay in the journal for the next start
+
+use ::*;
+use bender_job::{Task, Command, FrameMap};
+use work::transport::Transport;
+
+
+
+
+/// Defines what happens to running renders when the worker shuts down
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownMode{
+    /// Wait for running renders to finish and their frames to be uploaded
+    Drain,
+    /// Kill running renders and hand their Tasks back
+    Kill
+}
+
+impl Default for ShutdownMode {
+    fn default() -> Self {
+        ShutdownMode::Drain
+    }
+}
+
+impl ShutdownMode{
+    pub fn is_drain(&self) -> bool{
+        match *self{
+            ShutdownMode::Drain => true,
+            _                   => false
+        }
+    }
+
+    pub fn is_kill(&self) -> bool{
+        match *self{
+            ShutdownMode::Kill => true,
+            _                  => false
+        }
+    }
+}
+
+
+
+
+impl Work{
+
+    /// Runs every loop instead of `update()` once a shutdown has been requested. \
+    /// If `kill` is true, running renders are killed instead of drained. \
+    /// Returns true once the worker is done and can exit.
+    pub fn shutdown<T>(&mut self, kill: bool, transport: &mut T) -> bool where T: Transport{
+        if !self.shutting_down{
+            self.shutting_down = true;
+            if kill{
+                scrnmsg("Shutting down, killing running renders");
+            }else{
+                scrnmsg("Shutting down, draining running renders (send the signal again to kill them)");
+            }
+        }
+
+        // Retries might have queued Tasks again meanwhile, so do this every time
+        self.hand_back_unstarted(transport);
+
+        if kill{
+            self.kill_running(transport);
+        }
+
+        // Keep supervising what is left
+        self.run_command(transport);
+        self.stat_finished(transport);
+        self.upload_finished(transport);
+        self.beat_heart(transport);
+        self.write_journal();
+
+        let done = self.slots.iter().all(|slot| slot.is_free()) && (kill || !self.has_pending_uploads());
+        if done{
+            let routing_key = format!("shutdown.{}", self.config.id);
+            transport.post_event(routing_key, Vec::new());
+            okrun("Shut down cleanly");
+        }else{
+            self.sleep();
+        }
+        done
+    }
+
+
+    /// Remove all Tasks that haven't ended yet from self.tasks and requeue \
+    /// their deliveries, so another worker can take them
+    pub fn hand_back_unstarted<T>(&mut self, transport: &mut T) where T: Transport{
+        let (unstarted, rest): (Vec<Task>, Vec<Task>) = self.tasks.drain(..)
+                                                                .partition(|t| !t.is_ended());
+        self.tasks = rest;
+        for t in unstarted.iter(){
+            hand_back(t, transport);
+        }
+    }
+
+
+    /// Kill all running renders and requeue the deliveries of their Tasks
+    pub fn kill_running<T>(&mut self, transport: &mut T) where T: Transport{
+        for slot in self.slots.iter_mut().filter(|slot| slot.is_busy()){
+            if let Some(ref mut render) = slot.render{
+                if let Err(err) = render.kill(){
+                    errrun(format!("Couldn't kill render (pid {}): {}", render.id(), err));
+                }
+            }
+            if let Some(t) = slot.clear(){
+                hand_back(&t, transport);
+            }
+        }
+    }
+
+
+    /// Returns true if there are finished Tasks whose frames still wait for \
+    /// their upload. Without a frame sink nothing will ever be uploaded, so \
+    /// there is nothing to wait for. The in place sink of server mode marks \
+    /// the frames as uploaded on the next upload run
+    pub fn has_pending_uploads(&self) -> bool{
+        self.sink.is_some() &&
+        self.tasks.iter()
+                  .filter(|t| t.is_finished())
+                  .any(|t|{
+                    if let Command::Blender(ref b) = t.command{
+                        !b.frame.all_uploaded()
+                    }else{
+                        false
+                    }
+                  })
+    }
+}
+
+
+/// Requeue the delivery of the given Task
+pub fn hand_back<T>(task: &Task, transport: &mut T) where T: Transport{
+    match task.data.get("task-delivery-tag").and_then(|tag| tag.parse::<u64>().ok()){
+        Some(tag) => {
+            match transport.nack(tag, true){
+                Ok(_) => println!(" ↩ [WORKER][{task_id}][{parent_id}][{short}] Handed Task back",
+                    task_id=&task.id[..6],
+                    parent_id=&task.parent_id[..6],
+                    short=task.command.short()),
+                Err(err) => errrun(format!("[{}] Couldn't hand back Task: {}", &task.id[..6], err))
+            }
+        },
+        None => errrun(format!("[{}] Couldn't hand back Task, it has no delivery tag", &task.id[..6]))
+    }
+}
@@ -0,0 +1,74 @@
+//! The work::slots module defines the render Slot. The number of slots is \
+//! defined by the `render_slots` setting in the config, each slot renders at \
+//! most one Task at a time. This allows big machines to render multiple light \
+//! Tasks concurrently.
+
+use ::*;
+use bender_job::Task;
+use chrono::{Utc, DateTime, Duration};
+use work::render::Render;
+use work::progress::Progress;
+use work::logs::RenderLog;
+
+
+
+
+/// A Slot holds a Task, the render (spawned command) for it and the time when \
+/// it was started, as well as the progress and the log of the render
+#[derive(Debug, Default)]
+pub struct Slot{
+    pub task: Option<Task>,
+    pub render: Option<Render>,
+    pub started: Option<DateTime<Utc>>,
+    pub progress: Progress,
+    pub last_progress_post: Option<DateTime<Utc>>,
+    pub log: Option<RenderLog>
+}
+
+
+impl Slot{
+    /// Create a new empty Slot
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Create n empty Slots. At least one Slot is always returned
+    pub fn many(n: usize) -> Vec<Self>{
+        (0..std::cmp::max(n, 1)).map(|_| Self::new()).collect()
+    }
+
+    /// Returns true if there is no Task in the Slot
+    pub fn is_free(&self) -> bool{
+        self.task.is_none()
+    }
+
+    /// Returns true if there is a Task in the Slot
+    pub fn is_busy(&self) -> bool{
+        self.task.is_some()
+    }
+
+    /// Put a Task into the Slot and start the clock
+    pub fn occupy(&mut self, task: Task){
+        self.task = Some(task);
+        self.render = None;
+        self.started = Some(Utc::now());
+        self.progress = Progress::new();
+        self.last_progress_post = None;
+        self.log = None;
+    }
+
+    /// Empty the Slot and return the Task it held (if any)
+    pub fn clear(&mut self) -> Option<Task>{
+        self.render = None;
+        self.started = None;
+        self.progress = Progress::new();
+        self.last_progress_post = None;
+        self.log = None;
+        self.task.take()
+    }
+
+    /// Returns the duration since the Task in this Slot has been started
+    pub fn elapsed(&self) -> Option<Duration>{
+        self.started.map(|started| Utc::now() - started)
+    }
+}
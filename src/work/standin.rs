@@ -0,0 +1,376 @@
+//! The work::standin module is a small stand-in for the receiving side of the \
+//! frame uploads of flaskbender. It implements both the plain multipart upload \
+//! (`POST /job/<job-id>/<task-id>`) and the chunked protocol described in \
+//! `work::chunked`, and stores everything it receives below a directory.
+//!
+//! It also accepts S3-style `PUT /<bucket>/<key>` requests like a MinIO would, \
+//! so the S3 sink of `work::sinks` can be tested. If S3 credentials are set, \
+//! the signature of every request is checked.
+//!
+//! It only speaks as much HTTP/1.1 as the worker needs and is meant for local \
+//! testing (e.g. with `cargo run --example upload_standin`), not for production.
+
+use ::*;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::fs::{File, OpenOptions};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use sha2::{Sha256, Digest};
+use work::requests::{sha256_hex, to_hex};
+use work::sinks::{self, SigningRequest};
+
+
+
+
+/// A minimal HTTP request
+#[derive(Debug, Clone)]
+struct Request{
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>
+}
+
+impl Request{
+    /// Return the header with the given (case insensitive) name
+    fn header(&self, name: &str) -> Option<&str>{
+        self.headers.get(&name.to_lowercase()).map(|value| value.as_str())
+    }
+}
+
+
+/// A minimal HTTP response
+#[derive(Debug, Clone)]
+struct Response{
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>
+}
+
+impl Response{
+    fn new(status: u16, reason: &'static str) -> Self{
+        Response{ status, reason, headers: Vec::new(), body: Vec::new() }
+    }
+
+    fn with_header<S>(mut self, name: &str, value: S) -> Self where S: Into<String>{
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    fn with_body<S>(mut self, body: S) -> Self where S: Into<String>{
+        self.body = body.into().into_bytes();
+        self
+    }
+
+    fn write_to<W>(&self, writer: &mut W) -> io::Result<()> where W: Write{
+        write!(writer, "HTTP/1.1 {} {}\r\n", self.status, self.reason)?;
+        for (name, value) in self.headers.iter(){
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        write!(writer, "Content-Length: {}\r\nConnection: close\r\n\r\n", self.body.len())?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+
+
+
+/// The upload stand-in, storing received frames below `directory`
+#[derive(Debug)]
+pub struct StandIn{
+    listener: TcpListener,
+    directory: PathBuf,
+    s3_credentials: Option<(String, String)>
+}
+
+
+impl StandIn{
+    /// Bind the stand-in to the given address (e.g. `127.0.0.1:5000`)
+    pub fn bind<A, P>(address: A, directory: P) -> io::Result<Self> where A: ToSocketAddrs, P: Into<PathBuf>{
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(StandIn{
+            listener: TcpListener::bind(address)?,
+            directory,
+            s3_credentials: None
+        })
+    }
+
+    /// Check the signature of S3 requests against the given keys
+    pub fn with_s3_credentials<S>(mut self, access_key: S, secret_key: S) -> Self where S: Into<String>{
+        self.s3_credentials = Some((access_key.into(), secret_key.into()));
+        self
+    }
+
+    /// Return the URL to use as `bender_url` for the worker
+    pub fn url(&self) -> io::Result<String>{
+        Ok(format!("http://{}", self.listener.local_addr()?))
+    }
+
+    /// Handle connections until the process gets killed. Each connection is \
+    /// handled on its own thread
+    pub fn serve(&self){
+        for stream in self.listener.incoming(){
+            match stream{
+                Ok(stream) => {
+                    let directory = self.directory.clone();
+                    let s3_credentials = self.s3_credentials.clone();
+                    thread::spawn(move ||{
+                        if let Err(err) = handle_connection(stream, &directory, s3_credentials.as_ref()){
+                            eprintln!(" ✖ [STANDIN] Error: {}", err);
+                        }
+                    });
+                },
+                Err(err) => eprintln!(" ✖ [STANDIN] Error: Couldn't accept connection: {}", err)
+            }
+        }
+    }
+}
+
+
+
+
+/// Read a request from the stream, handle it and write the response
+fn handle_connection(mut stream: TcpStream, directory: &Path, s3_credentials: Option<&(String, String)>) -> io::Result<()>{
+    let request = read_request(&mut BufReader::new(stream.try_clone()?))?;
+    let response = match handle(&request, directory, s3_credentials){
+        Ok(response) => response,
+        Err(err) => Response::new(500, "Internal Server Error").with_body(format!("{}", err))
+    };
+    println!(" @ [STANDIN] {} {} -> {}", request.method, request.path, response.status);
+    response.write_to(&mut stream)
+}
+
+
+/// Dispatch the request to the matching endpoint
+fn handle(request: &Request, directory: &Path, s3_credentials: Option<&(String, String)>) -> io::Result<Response>{
+    let segments: Vec<&str> = request.path
+                                     .split('?').next().unwrap_or("")
+                                     .split('/')
+                                     .filter(|segment| !segment.is_empty())
+                                     .collect();
+    if segments.iter().any(|segment| segment.contains("..") || segment.contains('\\')){
+        return Ok(Response::new(400, "Bad Request").with_body("Invalid path"));
+    }
+
+    match (request.method.as_str(), segments.as_slice()){
+        ("POST", ["job", job, task]) => multipart_upload(request, &directory.join(job).join(task)),
+        ("HEAD", ["job", job, task, "chunked", name]) => chunk_offset(&directory.join(job).join(task).join(name)),
+        ("PATCH", ["job", job, task, "chunked", name]) => chunk_append(request, &directory.join(job).join(task).join(name)),
+        ("POST", ["job", job, task, "chunked", name]) => chunk_finish(request, &directory.join(job).join(task).join(name)),
+        ("PUT", _) if segments.len() >= 2 => s3_put(request, &directory.join(segments.iter().collect::<PathBuf>()), s3_credentials),
+        _ => Ok(Response::new(404, "Not Found"))
+    }
+}
+
+
+/// Return the path of the incomplete file for a chunked upload
+fn part_path(path: &Path) -> PathBuf{
+    work::requests::part_path(path)
+}
+
+
+/// `HEAD`: tell how many bytes have been received so far
+fn chunk_offset(path: &Path) -> io::Result<Response>{
+    match fs::metadata(part_path(path)){
+        Ok(metadata) => Ok(Response::new(200, "OK").with_header(work::chunked::UPLOAD_OFFSET, metadata.len().to_string())),
+        Err(_) => Ok(Response::new(404, "Not Found"))
+    }
+}
+
+
+/// `PATCH`: append a chunk, if it starts where the last one ended
+fn chunk_append(request: &Request, path: &Path) -> io::Result<Response>{
+    let offset = match request.header(work::chunked::UPLOAD_OFFSET).and_then(|offset| offset.parse::<u64>().ok()){
+        Some(offset) => offset,
+        None => return Ok(Response::new(400, "Bad Request").with_body("Missing Upload-Offset"))
+    };
+    if let Some(parent) = path.parent(){
+        fs::create_dir_all(parent)?;
+    }
+    let part = part_path(path);
+    let received = fs::metadata(&part).map(|metadata| metadata.len()).unwrap_or(0);
+    if offset != received{
+        return Ok(Response::new(409, "Conflict").with_header(work::chunked::UPLOAD_OFFSET, received.to_string()));
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&part)?;
+    file.write_all(&request.body)?;
+    let received = received + request.body.len() as u64;
+    Ok(Response::new(204, "No Content").with_header(work::chunked::UPLOAD_OFFSET, received.to_string()))
+}
+
+
+/// `POST`: check the size and hash of the received file and move it into place
+fn chunk_finish(request: &Request, path: &Path) -> io::Result<Response>{
+    let part = part_path(path);
+    let received = match fs::metadata(&part){
+        Ok(metadata) => metadata.len(),
+        Err(_) => return Ok(Response::new(404, "Not Found"))
+    };
+    let length = request.header(work::chunked::UPLOAD_LENGTH).and_then(|length| length.parse::<u64>().ok());
+    if length != Some(received){
+        return Ok(Response::new(409, "Conflict").with_header(work::chunked::UPLOAD_OFFSET, received.to_string()));
+    }
+    let hash = sha256_hex(&part).map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{}", err)))?;
+    match request.header(work::chunked::UPLOAD_SHA256){
+        Some(expected) if expected.eq_ignore_ascii_case(&hash) => {
+            fs::rename(&part, path)?;
+            Ok(Response::new(201, "Created"))
+        },
+        _ => {
+            fs::remove_file(&part)?;
+            Ok(Response::new(422, "Unprocessable Entity").with_body(format!("SHA-256 of the received file is {}", hash)))
+        }
+    }
+}
+
+
+/// `PUT` an object into a bucket. The payload hash is always checked, the \
+/// signature only if credentials are set
+fn s3_put(request: &Request, path: &Path, s3_credentials: Option<&(String, String)>) -> io::Result<Response>{
+    let payload_hash = to_hex(&Sha256::digest(&request.body));
+    if request.header("x-amz-content-sha256") != Some(payload_hash.as_str()){
+        return Ok(Response::new(400, "Bad Request").with_body("XAmzContentSHA256Mismatch"));
+    }
+
+    if let Some(&(ref access_key, ref secret_key)) = s3_credentials{
+        let authorization = request.header("authorization").unwrap_or("");
+        // The region is part of the scope: Credential=<key>/<day>/<region>/s3/aws4_request
+        let region = authorization.split("Credential=").nth(1)
+                                  .and_then(|credential| credential.split('/').nth(2))
+                                  .unwrap_or("");
+        let expected = sinks::sign_v4(&SigningRequest{
+            access_key,
+            secret_key,
+            region,
+            method: "PUT",
+            host: request.header("host").unwrap_or(""),
+            uri: request.path.split('?').next().unwrap_or(""),
+            query: "",
+            amz_date: request.header("x-amz-date").unwrap_or(""),
+            payload_hash: &payload_hash
+        });
+        if expected != authorization{
+            return Ok(Response::new(403, "Forbidden").with_body("SignatureDoesNotMatch"));
+        }
+    }
+
+    if let Some(parent) = path.parent(){
+        fs::create_dir_all(parent)?;
+    }
+    let part = part_path(path);
+    File::create(&part)?.write_all(&request.body)?;
+    fs::rename(&part, path)?;
+    Ok(Response::new(200, "OK"))
+}
+
+
+/// `POST` a whole frame as `multipart/form-data`
+fn multipart_upload(request: &Request, directory: &Path) -> io::Result<Response>{
+    let boundary = request.header("content-type")
+                          .and_then(|content_type| content_type.split("boundary=").nth(1))
+                          .map(|boundary| format!("--{}", boundary.trim_matches('"')));
+    let boundary = match boundary{
+        Some(boundary) => boundary,
+        None => return Ok(Response::new(400, "Bad Request").with_body("Not a multipart request"))
+    };
+
+    fs::create_dir_all(directory)?;
+    let mut stored = 0;
+    for part in split(&request.body, boundary.as_bytes()).into_iter().skip(1){
+        // Every part starts with its headers, separated from the content by an empty line
+        let header_end = match find(part, b"\r\n\r\n"){
+            Some(i) => i,
+            None => continue
+        };
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        let filename = headers.split("filename=\"").nth(1).and_then(|rest| rest.split('"').next());
+        if let Some(filename) = filename{
+            let name = Path::new(filename).file_name().map(|name| name.to_os_string());
+            if let Some(name) = name{
+                // The content ends with the CRLF in front of the next boundary
+                let content = &part[header_end+4..];
+                let content = if content.ends_with(b"\r\n") { &content[..content.len()-2] } else { content };
+                File::create(directory.join(name))?.write_all(content)?;
+                stored += 1;
+            }
+        }
+    }
+    if stored > 0{
+        Ok(Response::new(200, "OK"))
+    }else{
+        Ok(Response::new(400, "Bad Request").with_body("No file in the request"))
+    }
+}
+
+
+
+
+/// Read the request line, the headers and the body (either by Content-Length \
+/// or in chunked transfer encoding)
+fn read_request<R>(reader: &mut R) -> io::Result<Request> where R: BufRead{
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let path = parts.next().unwrap_or("/").to_string();
+
+    let mut headers = HashMap::new();
+    loop{
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty(){
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (header.next(), header.next()){
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let mut body = Vec::new();
+    let is_chunked = headers.get("transfer-encoding")
+                            .map(|encoding| encoding.to_lowercase().contains("chunked"))
+                            .unwrap_or(false);
+    if is_chunked{
+        loop{
+            let mut size = String::new();
+            reader.read_line(&mut size)?;
+            let size = usize::from_str_radix(size.trim().split(';').next().unwrap_or(""), 16)
+                             .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let mut chunk = vec![0u8; size + 2];
+            reader.read_exact(&mut chunk)?;
+            body.extend_from_slice(&chunk[..size]);
+            if size == 0{
+                break;
+            }
+        }
+    }else if let Some(length) = headers.get("content-length").and_then(|length| length.parse::<usize>().ok()){
+        body = vec![0u8; length];
+        reader.read_exact(&mut body)?;
+    }
+
+    Ok(Request{ method, path, headers, body })
+}
+
+
+/// Return the index of the first occurence of needle in haystack
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize>{
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+
+/// Split haystack at every occurence of the separator
+fn split<'a>(haystack: &'a [u8], separator: &[u8]) -> Vec<&'a [u8]>{
+    let mut parts = Vec::new();
+    let mut rest = haystack;
+    while let Some(i) = find(rest, separator){
+        parts.push(&rest[..i]);
+        rest = &rest[i+separator.len()..];
+    }
+    parts.push(rest);
+    parts
+}
@@ -6,13 +6,18 @@
 use ::*;
 use std::thread::sleep;
 use std::time::Duration;
-use amqp::Basic;
+use chrono::Utc;
 use bender_job::{Task, Command, FrameMap};
-use bender_mq::BenderMQ;
 use work::blendfiles::format_duration;
+use work::transport::Transport;
+use work::retry::{self, Failure};
+use work::uploads;
 use blend::Blend;
 
 
+/// How many lines of the render log get attached to an errored Task
+const LOG_TAIL_LINES: usize = 30;
+
 
 
 impl Work{
@@ -37,49 +42,68 @@ impl Work{
         }
     }
 
-    /// Listen in to work queue and get n messages (defined by the workload setting)
+    /// Listen in to the transports work queue and get n messages (defined by the workload setting)
     /// Store these in a Option-wrapped Work struct (along with...)
     /// Reject these messages if our system is not fit to do work
     /// Acknowledge messages that are wonky
-    pub fn get_tasks(&mut self, channel: &mut Channel){
+    pub fn get_tasks<T>(&mut self, transport: &mut T) where T: Transport{
         if self.should_add(){
             let mut remaining_delivery_tags = Vec::<u64>::new();
 
             // Get the next task from the work queue
-            channel.basic_get("work", false)
-                   .take(1)
-                   .for_each(|message|{
-                        match Task::deserialize_from_u8(&message.body){
-                            Ok(mut t) => {
-                                // Add Delivery tag to task data for later acknowledgement
-                                t.add_data("task-delivery-tag", message.reply.delivery_tag.to_string().as_str());
-                                
-                                // Add this as a event to the tasks history
-                                let h = format!("[WORKER] Task arrived at Worker [{}] with delivery tag {}", self.config.id, &t.data["task-delivery-tag"]);
-                                self.add_history(h.as_str());
-                                
-                                // Set the status of the task to queued
-                                t.queue();
-
-                                println!(" ✚ [WORKER][{task_id}][{parent_id}][{short}] Received Task", 
-                                    task_id=&t.id[..6],
-                                    parent_id=&t.parent_id[..6],
-                                    short=t.command.short());
-                                // Add the newly modified Task to the queue
-                                self.tasks.push(t);
-                            },
-                            Err(err) => {
-                                eprintln!("{}", format!(" ✖ [WORKER] Error: Couldn't deserialize Task from message.body: {}", err).red());
-                                // Always try to acknowledge received messages that couldn't be decoded
-                                remaining_delivery_tags.push(message.reply.delivery_tag);
-                            }
+            if let Some(message) = transport.next_delivery(){
+                match Task::deserialize_from_u8(&message.body){
+                    // This worker can't render the job, leave it to the others
+                    Ok(ref t) if self.rejected_jobs.contains_key(&t.parent_id) => {
+                        match transport.nack(message.tag, true){
+                            Ok(_) => println!(" ↩ [WORKER][{task_id}][{parent_id}][{short}] Handed Task of rejected job back",
+                                task_id=&t.id[..6],
+                                parent_id=&t.parent_id[..6],
+                                short=t.command.short()),
+                            Err(err) => errrun(format!("[{}] Couldn't hand back Task of rejected job: {}", &t.id[..6], err))
                         }
-                    });
+                    },
+                    Ok(mut t) => {
+                        // Add Delivery tag to task data for later acknowledgement
+                        t.add_data("task-delivery-tag", message.tag.to_string().as_str());
+                        // Remember whether someone else gave this Task back before. \
+                        // Handing it back for a paused job doesn't count, and it \
+                        // is kept this time (see work::pause)
+                        let redelivered = match self.paused_hand_backs.remove(&t.id){
+                            Some(redelivered) => {
+                                t.add_data("paused-hand-back", "true");
+                                redelivered
+                            },
+                            None => message.redelivered
+                        };
+                        t.add_data("task-redelivered", redelivered.to_string().as_str());
+                        
+                        // Add this as a event to the tasks history
+                        let h = format!("[WORKER] Task arrived at Worker [{}] with delivery tag {}", self.config.id, &t.data["task-delivery-tag"]);
+                        self.add_history(h.as_str());
+                        
+                        // Set the status of the task to queued
+                        t.queue();
+
+                        println!(" ✚ [WORKER][{task_id}][{parent_id}][{short}] Received Task", 
+                            task_id=&t.id[..6],
+                            parent_id=&t.parent_id[..6],
+                            short=t.command.short());
+                        // Add the newly modified Task to the queue
+                        self.tasks.push(t);
+                    },
+                    Err(err) => {
+                        eprintln!("{}", format!(" ✖ [WORKER] Error: Couldn't deserialize Task from message.body: {}", err).red());
+                        // Always try to acknowledge received messages that couldn't be decoded
+                        remaining_delivery_tags.push(message.tag);
+                    }
+                }
+            }
 
             // Acknowledge all remaining wonky messages, that had their deserialization failed
             // to avoid the accumulation of garbage in the queue
             for tag in remaining_delivery_tags.iter(){
-                if let Err(err) = channel.basic_ack(*tag, false){
+                if let Err(err) = transport.ack(*tag){
                     eprintln!("{}", format!(" ✖ [WORKER] Error: acknowledgment failed for received message: {}", err).red());
                 }
             }
@@ -89,7 +113,12 @@ impl Work{
 
     /// Returns true if there is at least one task
     pub fn has_task(&self) -> bool{
-        !self.tasks.is_empty() || self.current.is_some()
+        !self.tasks.is_empty() || self.slots.iter().any(|slot| slot.is_busy())
+    }
+
+    /// Returns the index of the first free render slot (if any)
+    pub fn free_slot(&self) -> Option<usize>{
+        self.slots.iter().position(|slot| slot.is_free())
     }
 
     /// Return all tasks that have a given parent id
@@ -108,11 +137,11 @@ impl Work{
     }
 
 
-    /// Returns a Reference to the next Task only if there is no Task running
+    /// Returns a Reference to the next Task only if there is a free slot
     /// Only works on tasks with a constructed Command
     /// Doesn't affect the Tasks Status (use it too peek for next Task)
     pub fn next_task(&self) -> Option<&Task>{
-        if self.current.is_none() {
+        if self.free_slot().is_some() {
             self.tasks.iter()
                       .filter(|t| t.command.is_constructed())
                       .find(|t| t.is_queued())
@@ -121,24 +150,29 @@ impl Work{
         }
     }
 
-    /// Moves the next Task to self.current only if there is no Task running
+    /// Moves the next Tasks into free slots until either all slots are busy or \
+    /// there are no more Tasks to start
     /// Only works on tasks with a constructed Command
     /// Sets the Tasks Status to Running
-    pub fn select_next_task(&mut self, channel: &mut Channel){
+    pub fn select_next_task<T>(&mut self, transport: &mut T) where T: Transport{
         if self.has_task() && !self.all_jobs_finished() {
-            // Only do this if there is no current task running
-            if self.current.is_none(){
+            // Only do this as long as there is a free slot
+            while let Some(slot) = self.free_slot(){
                 let mut i = 0;
                 let mut next = None;
                 // Find the first task that:
-                // - has a blendfile
+                // - has a blendfile that passed the pre-flight validation
                 // - has a constructed command
                 // - is queued
+                // - is not waiting for a retry
+                // - doesn't belong to a paused job
                 // then remove this Task from the list and store it in next
                 while i < self.tasks.len() && next.is_none() {
-                    if self.blendfile_is_optimized(&self.tasks[i]) &&
+                    if self.blendfile_is_ready(&self.tasks[i]) &&
                         self.tasks[i].command.is_constructed() &&
                         (self.tasks[i].is_queued() || self.tasks[i].is_running()) &&
+                        retry::is_due(&self.tasks[i]) &&
+                        !self.is_paused(&self.tasks[i]) &&
                         next.is_none() {
                             println!(" ▷ [WORKER][{task_id}][{parent_id}][{short}] ◁--- Selected as next Task", 
                                 task_id=&self.tasks[i].id[..6],
@@ -147,40 +181,35 @@ impl Work{
                             next = Some(self.tasks.remove(i));
                     } else {
                             i += 1;
-                            // if i < self.tasks.len() {
-                            //     println!("SELECTION for {}", &self.tasks[i].id);
-                            //     println!("              has_blendfile:  {}", self.has_blendfile(&self.tasks[i]));
-                            //     println!("              is_constructed: {}", self.tasks[i].command.is_constructed());
-                            //     println!("              is_queued:      {}", self.tasks[i].is_queued());
-                            //     println!("              next.is_none:   {}\n", next.is_none());
-                            // }
                     }
                 }
 
-                // Match the result of above find operation and assign it to
-                // self.current only if there is an actual Task
-                if let Some(mut t) = next {
-                    t.start();
-                    println!(" ✚ [WORKER][{task_id}][{parent_id}][{short}] Queued Task", 
-                        task_id=&t.id[..6], 
-                        parent_id=&t.parent_id[..6],
-                        short=t.command.short());
-                    let routing_key = format!("start.{}", self.config.id);
-                    match t.serialize_to_u8(){
-                        Ok(task_json) => channel.worker_post(routing_key, task_json),
-                        Err(err) => eprintln!(" ✖ [WORKER] Error: Failed ot deserialize Task {}: {}", t.id, err)
-                    }
-                    
-                    self.current = Some(t);
+                // Match the result of above find operation and put it into
+                // the free slot only if there is an actual Task
+                match next {
+                    Some(mut t) => {
+                        t.start();
+                        println!(" ✚ [WORKER][{task_id}][{parent_id}][{short}] Queued Task in slot {slot}", 
+                            task_id=&t.id[..6], 
+                            parent_id=&t.parent_id[..6],
+                            short=t.command.short(),
+                            slot=slot);
+                        let routing_key = format!("start.{}", self.config.id);
+                        match t.serialize_to_u8(){
+                            Ok(task_json) => transport.post_event(routing_key, task_json),
+                            Err(err) => eprintln!(" ✖ [WORKER] Error: Failed ot deserialize Task {}: {}", t.id, err)
+                        }
+                        
+                        self.slots[slot].occupy(t);
+                    },
+                    None => break
                 }
-            }else{
-                 //println!("Debug: didn't get a new task because the old is running");
             }
         }
     }
 
     /// Get Filesizes and generate hashes for every rendered frame.
-    pub fn stat_finished(&mut self, channel: &mut Channel){
+    pub fn stat_finished<T>(&mut self, transport: &mut T) where T: Transport{
         if self.has_task(){
             // Set filesize for frames without it
             self.tasks.iter_mut()
@@ -210,7 +239,7 @@ impl Work{
                             // Post the updated Task Info
                             let routing_key = format!("stat.{}", task.parent_id);
                             match task.serialize_to_u8(){
-                                Ok(task_json) => channel.worker_post(routing_key, task_json),
+                                Ok(task_json) => transport.post_event(routing_key, task_json),
                                 Err(err) => eprintln!(" ✖ [WORKER] Error: Failed ot deserialize Task {}: {}", task.id, err)
                             }
                       });
@@ -243,7 +272,7 @@ impl Work{
                             // Post the updated Task Info
                             let routing_key = format!("stat.{}", task.parent_id);
                             match task.serialize_to_u8(){
-                                Ok(task_json) => channel.worker_post(routing_key, task_json),
+                                Ok(task_json) => transport.post_event(routing_key, task_json),
                                 Err(err) => eprintln!(" ✖ [WORKER] Error: Failed ot deserialize Task {}: {}", task.id, err)
                             }
                       });
@@ -251,14 +280,20 @@ impl Work{
     }
 
     /// Get Filesizes and generate hashes for every rendered frame.
-    pub fn upload_finished(&mut self, channel: &mut Channel){
+    pub fn upload_finished<T>(&mut self, transport: &mut T) where T: Transport{
         if self.has_task(){
             // Split the borrow
-            let Self{ tasks, last_upload, ..} = self;
+            let Self{ tasks, last_upload, sink, ..} = self;
             if last_upload.should_run(){
                 let worker_id = self.config.id;
-                let mode_is_independent = self.config.mode.is_independent();
-                let bender_url = self.config.bender_url.clone();
+                let sink = match *sink{
+                    Some(ref sink) => sink,
+                    None => {
+                        errrun("There is no frame sink to upload to, check the sink in the config");
+                        last_upload.set_last_failed();
+                        return;
+                    }
+                };
 
                 tasks.iter_mut()
                           .filter(|task|task.is_finished())
@@ -287,76 +322,45 @@ impl Work{
                             }
                           })
                           .for_each(|task|{
-                                if mode_is_independent{
-                                    let mut url = bender_url.clone();
-                                    url = url+"/job/"+&*task.parent_id.clone()+"/"+&*task.id.clone();
-                                    println!("{}", format!(" @ [WORKER][{task_id}][{parent_id}][{short}] Upload started", 
-                                        task_id=&task.id[..6], 
-                                        parent_id=&task.parent_id[..6], 
-                                        short=task.command.short()).blue());
-                                    match task.command.post_frames(url){
-                                        Ok(mut responses) => {
-                                            if responses[0].status().is_success(){
-                                                println!("{}", format!(" ✔️ [WORKER][{task_id}][{parent_id}][{short}] Upload sucessful", 
-                                                    task_id=&task.id[..6], 
-                                                    parent_id=&task.parent_id[..6], 
-                                                    short=&task.command.short()).blue());
-                                                if let Command::Blender(ref mut b) = task.command{
-                                                    b.set_all_uploaded().unwrap();
-                                                }
-                                                last_upload.set_last()
-                                            }else{
-                                                last_upload.set_last_failed();
-                                                errrun(format!("[{}][{}][{}] Server responded with: {:#?}", 
-                                                    &task.id[..6], 
-                                                    &task.parent_id[..6], 
-                                                    &task.command.short(),
-                                                    responses[0].text()
-                                                                .unwrap_or_else(|_| "Couldn't descramble response".to_string())));
-                                            }
-                                        },
-                                        Err(err) => {
-                                            last_upload.set_last_failed();
-                                            errrun(format!("[{}][{}] Couldn't post_frames for {}, failed with Error: {}",
-                                                &task.id[..6], 
-                                                &task.parent_id[..6], 
-                                                &task.command.short(), 
-                                                err))
-                                        }
-                                    }
-                                }else{
-                                    // Set uploaded right away if on server
-                                    if let Command::Blender(ref mut b) = task.command{
-                                        b.set_all_uploaded().unwrap();
-                                    }
-                                    last_upload.set_last();
+                                // Every frame is delivered (and retried) on its own
+                                if !uploads::upload_frames(task, &**sink){
+                                    return;
                                 }
 
                                 // Post the updated Task Info
                                 let routing_key = format!("stat.{}", worker_id);
                                 match task.serialize_to_u8(){
-                                    Ok(task_json) => channel.worker_post(routing_key, task_json),
+                                    Ok(task_json) => transport.post_event(routing_key, task_json),
                                     Err(err) => eprintln!(" ✖ [WORKER] Error: Failed ot deserialize Task {}: {}", task.id, err)
                                 }
                           });
+                // Failed frames have their own backoff, see work::uploads
+                last_upload.set_last();
 
             }
         }
     }
 
-    /// finish the current task and push it back to tasks
-    pub fn finish_current(&mut self, channel: &mut Channel){
-        let mut moved = false;
-        // let c =  self.clone();
-        if let Some(ref mut t) = self.current{
+    /// Finish the current task of the given slot, acknowledge its delivery and \
+    /// push it back to tasks
+    pub fn finish_current<T>(&mut self, slot: usize, transport: &mut T) where T: Transport{
+        let elapsed = self.slots[slot].elapsed();
+        if let Some(mut t) = self.slots[slot].clear(){
             t.finish();
+
+            // Record the Blender that actually rendered it with the Task \
+            // itself, so it travels along with the finish event
+            if let Some(blender) = t.data.get("blender").cloned(){
+                let h = format!("[WORKER] Task [{}] was rendered with Blender {} ({})", t.id, blender, t.data.get("blender-path").map(|p| p.as_str()).unwrap_or("unknown path"));
+                t.add_data("rendered-with", h.as_str());
+            }
             self.tasks.push(t.clone());
 
             // Ack the finished Task!
             let deliver_tag = &t.data["task-delivery-tag"]
                                 .parse::<u64>()
                                 .unwrap();
-            if let Err(err) = channel.basic_ack(*deliver_tag, false){
+            if let Err(err) = transport.ack(*deliver_tag){
                 eprintln!(" ✖ [WORKER] Error: Couldn't acknowledge task {} for job [{}]: {}", 
                     t.command.short(), 
                     t.parent_id,
@@ -366,16 +370,20 @@ impl Work{
             // Post the updated Task Info
             let routing_key = format!("finish.{}", self.config.id);
             match t.serialize_to_u8(){
-                Ok(task_json) => channel.worker_post(routing_key, task_json),
+                Ok(task_json) => transport.post_event(routing_key, task_json),
                 Err(err) => eprintln!(" ✖ [WORKER] Error: Failed ot deserialize Task {}: {}", &t.id[..6], err)
             }
 
-            moved = true;
             match self.blendfiles.get_mut(&t.parent_id){
                 Some(mut opt_bf) => {
                     match opt_bf{
                         Blend::Optimized(ref mut bf) => {
-                            bf.increment_frame();
+                            // Use the time spent in the slot, as other slots \
+                            // might have finished frames of the same job meanwhile
+                            match elapsed{
+                                Some(duration) => bf.add_frame(duration),
+                                None => bf.increment_frame()
+                            }
                             let duration = bf.last_frame_duration().unwrap();
                             let average = bf.average_duration();
                             println!("{}", format!(" ✔️ [WORKER][{task_id}][{parent_id}][{short}] Finished Task after: {duration} (Average: {average})", 
@@ -387,41 +395,79 @@ impl Work{
                             );
                         },
                         Blend::Downloaded(_) => eprintln!("{}", format!(" ✖ [WORKER] Error: Tried to finish the Job with the ID {} in self.blendfiles, but it was not optimized ... This shouldn't ever happen!", t.parent_id).red()),
-                        Blend::None => eprintln!("{}", format!(" ✖ [WORKER] Error: Tried to finish the Job with the ID {} in self.blendfiles, but it was None... This shouldn't ever happen!", t.parent_id).red())
+                        Blend::Downloading{..} | Blend::None => eprintln!("{}", format!(" ✖ [WORKER] Error: Tried to finish the Job with the ID {} in self.blendfiles, but it was None... This shouldn't ever happen!", t.parent_id).red())
                     }
                 },
                 None => eprintln!("{}", format!(" ✖ [WORKER] Error: Couldn't find Job with ID {} in self.blendfiles... This must be a bug!", t.parent_id).red())
             }
         }
-
-        if moved{
-            self.current = None;
-            self.command = None;
-        }
     }
     
 
-    /// Errors the current task and push it back to tasks
-    pub fn error_current<S>(&mut self, err: S, channel: &mut Channel) where S: Into<String>{
+    /// Handle a failure of the current task of the given slot. If the retry \
+    /// policy allows it, the task is queued again and started after a backoff \
+    /// period. Otherwise it is errored, pushed back to tasks and its delivery is \
+    /// handed back to the broker (requeued or rejected depending on the policy)
+    pub fn error_current<S, T>(&mut self, slot: usize, failure: Failure, err: S, transport: &mut T) where S: Into<String>, T: Transport{
         let err = err.into();
-        let mut moved = false;
-        // let c =  self.clone();
-        if let Some(ref mut t) = self.current{
-            t.error();
-            self.tasks.push(t.clone());
-            moved = true;
-            eprintln!("{}", format!(" ✖ [WORKER][{}][{}] Errored task for job: {}", &t.id[..6], &t.parent_id[..6], err).red());
-            let routing_key = format!("error.{}", self.config.id);
-            match t.serialize_to_u8(){
-                Ok(task_json) => channel.worker_post(routing_key, task_json),
-                Err(err) => eprintln!(" ✖ [WORKER][{}] Error: Failed to deserialize Task: {}", &t.id[..6], err)
+        // Get the end of the render log before the slot forgets about it
+        let log_tail = self.slots[slot].log.as_ref().map(|log| log.tail(LOG_TAIL_LINES));
+        if let Some(mut t) = self.slots[slot].clear(){
+            if let Some(log_tail) = log_tail{
+                t.add_data("log-tail", log_tail.as_str());
+            }
+            let attempt = retry::attempts(&t) + 1;
+            t.add_data("retry-attempts", attempt.to_string().as_str());
+            t.add_data("last-failure", format!("{:?}", failure).as_str());
+
+            if self.config.retry.should_retry(failure, attempt){
+                // Queue it again, but not before the backoff period is over
+                let backoff = self.config.retry.backoff(attempt);
+                t.add_data("retry-after", (Utc::now() + backoff).to_rfc3339().as_str());
+                t.queue();
+                eprintln!("{}", format!(" ↻ [WORKER][{}][{}] Task failed (attempt {}/{}), retrying in {}: {}", 
+                    &t.id[..6], 
+                    &t.parent_id[..6], 
+                    attempt, 
+                    self.config.retry.max_attempts, 
+                    format_duration(backoff), 
+                    err).yellow());
+                let h = format!("[WORKER] Task [{}] failed on attempt {} with {:?}, retrying", t.id, attempt, failure);
+                self.add_history(h.as_str());
+                let routing_key = format!("retry.{}", self.config.id);
+                match t.serialize_to_u8(){
+                    Ok(task_json) => transport.post_event(routing_key, task_json),
+                    Err(err) => eprintln!(" ✖ [WORKER][{}] Error: Failed to deserialize Task: {}", &t.id[..6], err)
+                }
+                self.tasks.push(t);
+            }else{
+                self.error_task(t, err.as_str(), transport);
             }
         }
+    }
+
 
-        if moved{
-            self.current = None;
-            self.command = None;
+    /// Error the given task for good, post it and hand its delivery back to \
+    /// the broker (requeued or rejected depending on the policy)
+    pub fn error_task<T>(&mut self, mut t: Task, err: &str, transport: &mut T) where T: Transport{
+        t.error();
+        eprintln!("{}", format!(" ✖ [WORKER][{}][{}] Errored task for job: {}", &t.id[..6], &t.parent_id[..6], err).red());
+        let routing_key = format!("error.{}", self.config.id);
+        match t.serialize_to_u8(){
+            Ok(task_json) => transport.post_event(routing_key, task_json),
+            Err(err) => eprintln!(" ✖ [WORKER][{}] Error: Failed to deserialize Task: {}", &t.id[..6], err)
+        }
+
+        // Give the delivery back, so the broker can hand it to a 
+        // different worker (unless that already happened before)
+        let redelivered = t.data.get("task-redelivered").map(|r| r == "true").unwrap_or(false);
+        let requeue = self.config.retry.requeue && !redelivered;
+        if let Some(tag) = t.data.get("task-delivery-tag").and_then(|tag| tag.parse::<u64>().ok()){
+            if let Err(err) = transport.nack(tag, requeue){
+                eprintln!(" ✖ [WORKER][{}] Error: Couldn't hand back delivery of errored task: {}", &t.id[..6], err);
+            }
         }
+        self.tasks.push(t);
     }
 
 
@@ -435,16 +481,22 @@ impl Work{
                         match blendfile{
                             Blend::Downloaded(bf) => task.add_data("blendfile", &bf.path.to_string_lossy()),
                             Blend::Optimized(bf) => task.add_data("blendfile", &bf.path.to_string_lossy()), // Should actually not happen
-                            Blend::None => ()
+                            Blend::Downloading{..} | Blend::None => ()
                         }
                     }
                   })
     }
 
 
+    /// Don't spin out of control: sleep long if there is nothing to do and \
+    /// only briefly if there are renders to check on
     pub fn sleep(&self){
         if !self.has_task(){
             sleep(Duration::from_millis(2000));
+        }else if self.slots.iter().any(|slot| slot.render.is_some()){
+            sleep(Duration::from_millis(100));
+        }else if self.is_downloading(){
+            sleep(Duration::from_millis(250));
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,241 @@
+//! The work::transport module decouples `Work` from the message broker. Tasks \
+//! are pulled from a `TaskSource`, and everything the worker has to say about \
+//! them is pushed into an `EventSink`.
+//!
+//! There are three implementations of both traits:
+//! - `bender_mq::Channel` talks to rabbitmq (the `work` queue and the worker \
+//!   exchange)
+//! - `RoutedChannel` does the same, but takes Tasks from several queues (see \
+//!   `work::capabilities`)
+//! - `MemoryQueue` keeps everything in memory, which allows to drive the whole \
+//!   life of a Task without a live broker (e.g. in tests or for local queues)
+
+use ::*;
+use std::collections::{HashMap, VecDeque};
+use amqp::{Basic, Table};
+use bender_job::Task;
+use bender_mq::BenderMQ;
+use config::GenResult;
+use work::capabilities::SHARED_QUEUE;
+
+
+
+
+/// A single message received from a `TaskSource`. The tag is used to \
+/// acknowledge the message later on. `redelivered` is true if the message has \
+/// been handed back to the source before (e.g. by another worker)
+#[derive(Debug, Clone)]
+pub struct Delivery{
+    pub tag: u64,
+    pub body: Vec<u8>,
+    pub redelivered: bool
+}
+
+
+/// Something Tasks can be received from and acknowledged at
+pub trait TaskSource{
+    /// Return the next delivery if there is one
+    fn next_delivery(&mut self) -> Option<Delivery>;
+
+    /// Acknowledge the delivery with the given tag
+    fn ack(&mut self, tag: u64) -> GenResult<()>;
+
+    /// Hand the delivery with the given tag back. If `requeue` is true it will \
+    /// be delivered again (possibly to someone else), otherwise it is dropped
+    fn nack(&mut self, tag: u64, requeue: bool) -> GenResult<()>;
+}
+
+
+/// Something events about Tasks (and the Worker) can be posted to
+pub trait EventSink{
+    /// Post a body with the given routing key (e.g. `start.<worker-id>`)
+    fn post_event(&mut self, routing_key: String, body: Vec<u8>);
+}
+
+
+/// Everything `Work::update()` needs to run. This is implemented for every \
+/// type that is both a `TaskSource` and an `EventSink`
+pub trait Transport: TaskSource + EventSink {}
+
+impl<T> Transport for T where T: TaskSource + EventSink {}
+
+
+
+
+impl TaskSource for Channel{
+    fn next_delivery(&mut self) -> Option<Delivery>{
+        self.basic_get("work", false)
+            .next()
+            .map(|message| Delivery{ 
+                tag: message.reply.delivery_tag, 
+                redelivered: message.reply.redelivered, 
+                body: message.body 
+            })
+    }
+
+    fn ack(&mut self, tag: u64) -> GenResult<()>{
+        self.basic_ack(tag, false)?;
+        Ok(())
+    }
+
+    fn nack(&mut self, tag: u64, requeue: bool) -> GenResult<()>{
+        self.basic_nack(tag, false, requeue)?;
+        Ok(())
+    }
+}
+
+
+impl EventSink for Channel{
+    fn post_event(&mut self, routing_key: String, body: Vec<u8>){
+        self.worker_post(routing_key, body);
+    }
+}
+
+
+
+
+/// A `Channel` that takes Tasks from the given queues in turn, the first one \
+/// that has a message wins. Delivery tags are per channel, so acknowledging \
+/// works the same for all of them
+pub struct RoutedChannel{
+    pub channel: Channel,
+    pub queues: Vec<String>
+}
+
+
+impl RoutedChannel{
+    /// Wrap the channel
+    pub fn new(channel: Channel, queues: Vec<String>) -> Self{
+        RoutedChannel{ channel, queues }
+    }
+
+    /// Declare the capability queues (durable), so Tasks can be routed to \
+    /// them before this worker ever asked for one. The shared `work` queue is \
+    /// declared by `BenderMQ::create_work_queue()` already
+    pub fn declare_queues(&mut self) -> GenResult<()>{
+        for queue in self.queues.iter().filter(|queue| queue.as_str() != SHARED_QUEUE){
+            self.channel.queue_declare(queue.as_str(), false, true, false, false, false, Table::new())?;
+        }
+        Ok(())
+    }
+}
+
+
+impl TaskSource for RoutedChannel{
+    fn next_delivery(&mut self) -> Option<Delivery>{
+        for queue in self.queues.iter(){
+            let message = self.channel.basic_get(queue.as_str(), false).next();
+            if let Some(message) = message{
+                return Some(Delivery{
+                    tag: message.reply.delivery_tag,
+                    redelivered: message.reply.redelivered,
+                    body: message.body
+                });
+            }
+        }
+        None
+    }
+
+    fn ack(&mut self, tag: u64) -> GenResult<()>{
+        self.channel.ack(tag)
+    }
+
+    fn nack(&mut self, tag: u64, requeue: bool) -> GenResult<()>{
+        self.channel.nack(tag, requeue)
+    }
+}
+
+
+impl EventSink for RoutedChannel{
+    fn post_event(&mut self, routing_key: String, body: Vec<u8>){
+        self.channel.post_event(routing_key, body);
+    }
+}
+
+
+
+
+/// A in-memory stand-in for rabbitmq. Deliveries are handed out in the order \
+/// they were pushed and stay unacknowledged until `ack()` is called. All posted \
+/// events are recorded in `events`.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryQueue{
+    next_tag: u64,
+    queued: VecDeque<Delivery>,
+    unacked: HashMap<u64, Vec<u8>>,
+    pub events: Vec<(String, Vec<u8>)>
+}
+
+
+impl MemoryQueue{
+    /// Create a new empty MemoryQueue
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Push a raw message body to the queue and return its delivery tag
+    pub fn push<B>(&mut self, body: B) -> u64 where B: Into<Vec<u8>>{
+        self.next_tag += 1;
+        self.queued.push_back(Delivery{ tag: self.next_tag, body: body.into(), redelivered: false });
+        self.next_tag
+    }
+
+    /// Serialize a Task and push it to the queue
+    pub fn push_task(&mut self, task: &Task) -> GenResult<u64>{
+        let body = task.serialize_to_u8()?;
+        Ok(self.push(body))
+    }
+
+    /// Number of deliveries that have not been handed out yet
+    pub fn queued_count(&self) -> usize{
+        self.queued.len()
+    }
+
+    /// Number of deliveries that have been handed out, but not acknowledged
+    pub fn unacked_count(&self) -> usize{
+        self.unacked.len()
+    }
+
+    /// Return all posted events whose routing key starts with the given prefix \
+    /// (e.g. `"finish."`)
+    pub fn events_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a (String, Vec<u8>)> + 'a{
+        self.events.iter()
+                   .filter(move |(key, _)| key.starts_with(prefix))
+    }
+}
+
+
+impl TaskSource for MemoryQueue{
+    fn next_delivery(&mut self) -> Option<Delivery>{
+        let delivery = self.queued.pop_front()?;
+        self.unacked.insert(delivery.tag, delivery.body.clone());
+        Some(delivery)
+    }
+
+    fn ack(&mut self, tag: u64) -> GenResult<()>{
+        match self.unacked.remove(&tag){
+            Some(_) => Ok(()),
+            None => Err(From::from(format!("Unknown delivery tag {}", tag)))
+        }
+    }
+
+    fn nack(&mut self, tag: u64, requeue: bool) -> GenResult<()>{
+        match self.unacked.remove(&tag){
+            Some(body) => {
+                if requeue{
+                    self.next_tag += 1;
+                    self.queued.push_back(Delivery{ tag: self.next_tag, body, redelivered: true });
+                }
+                Ok(())
+            },
+            None => Err(From::from(format!("Unknown delivery tag {}", tag)))
+        }
+    }
+}
+
+
+impl EventSink for MemoryQueue{
+    fn post_event(&mut self, routing_key: String, body: Vec<u8>){
+        self.events.push((routing_key, body));
+    }
+}
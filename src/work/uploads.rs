@@ -0,0 +1,231 @@
+//! The work::uploads module keeps track of the upload state of every single \
+//! frame of a finished Task. Each frame is handed to the configured FrameSink \
+//! (see `work::sinks`) on its own, only the frames it accepted are marked as \
+//! uploaded, and failed ones are retried with their own exponential backoff.
+//!
+//! When posting to flaskbender, frames larger than `upload_chunk_bytes` (if \
+//! it isn't 0) are sent with the resumable chunked protocol (see \
+//! `work::chunked`), so a broken link only costs the chunk that was in flight. \
+//! If the server doesn't know the chunked endpoint, the frame is posted in a \
+//! single request instead.
+//!
+//! The state lives in the Tasks data under `frame-uploads` (as JSON), so it \
+//! is part of the journal and of the `stat.<worker-id>` events.
+
+use ::*;
+use std::collections::BTreeMap;
+use bender_job::{Task, Command};
+use chrono::{Utc, DateTime, Duration};
+use config::GenResult;
+use reqwest::Method;
+use work::chunked;
+use work::client::BenderClient;
+use work::requests::sha256_hex;
+use work::sinks::FrameSink;
+
+/// Seconds to wait before retrying a failed frame, doubled for each further try
+const UPLOAD_BACKOFF_SECONDS: i64 = 5;
+/// The longest a failed frame waits for its next try
+const UPLOAD_BACKOFF_MAX_SECONDS: i64 = 600;
+
+
+
+
+/// The upload state of a single frame
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FrameUpload{
+    pub uploaded: bool,
+    pub attempts: usize,
+    pub retry_after: Option<DateTime<Utc>>,
+    pub last_error: Option<String>
+}
+
+
+impl FrameUpload{
+    /// Returns true if the frame still needs to be uploaded and its backoff \
+    /// period (if any) is over
+    pub fn is_due(&self) -> bool{
+        !self.uploaded && self.retry_after.map(|after| Utc::now() >= after).unwrap_or(true)
+    }
+}
+
+
+/// The upload states of all frames of a Task, keyed by the path of the frame
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FrameUploads{
+    pub frames: BTreeMap<String, FrameUpload>
+}
+
+
+impl FrameUploads{
+    /// Read the upload states from the Tasks data and add a fresh one for every \
+    /// rendered frame that has none yet
+    pub fn from_task(task: &Task) -> Self{
+        let mut uploads: Self = task.data.get("frame-uploads")
+                                         .and_then(|json| serde_json::from_str(json).ok())
+                                         .unwrap_or_default();
+        for path in renderpaths(task){
+            uploads.frames.entry(path.to_string_lossy().to_string())
+                          .or_insert_with(FrameUpload::default);
+        }
+        uploads
+    }
+
+    /// Write the upload states to the Tasks data
+    pub fn store(&self, task: &mut Task){
+        match serde_json::to_string(self){
+            Ok(json) => task.add_data("frame-uploads", &json),
+            Err(err) => errrun(format!("[{}] Couldn't store the upload state of the frames: {}", &task.id[..6], err))
+        }
+    }
+
+    /// Return the paths of all frames whose upload is due
+    pub fn due(&self) -> Vec<PathBuf>{
+        self.frames.iter()
+                   .filter(|(_, frame)| frame.is_due())
+                   .map(|(path, _)| PathBuf::from(path))
+                   .collect()
+    }
+
+    /// Mark the frame at the given path as uploaded
+    pub fn set_uploaded(&mut self, path: &Path){
+        let frame = self.frames.entry(path.to_string_lossy().to_string()).or_insert_with(FrameUpload::default);
+        frame.uploaded = true;
+        frame.attempts += 1;
+        frame.retry_after = None;
+        frame.last_error = None;
+    }
+
+    /// Mark the upload of the frame at the given path as failed and schedule \
+    /// the next try
+    pub fn set_failed<S>(&mut self, path: &Path, error: S) where S: Into<String>{
+        let frame = self.frames.entry(path.to_string_lossy().to_string()).or_insert_with(FrameUpload::default);
+        frame.attempts += 1;
+        frame.retry_after = Some(Utc::now() + backoff(frame.attempts));
+        frame.last_error = Some(error.into());
+    }
+
+    /// Returns true if every frame has been uploaded
+    pub fn all_uploaded(&self) -> bool{
+        self.frames.values().all(|frame| frame.uploaded)
+    }
+
+    /// Return the number of uploaded frames
+    pub fn uploaded_count(&self) -> usize{
+        self.frames.values().filter(|frame| frame.uploaded).count()
+    }
+}
+
+
+
+
+/// Return the backoff period after the nth failed try
+pub fn backoff(attempt: usize) -> Duration{
+    let exponent = std::cmp::min(attempt.saturating_sub(1), 16) as u32;
+    let seconds = std::cmp::min(UPLOAD_BACKOFF_SECONDS * 2i64.pow(exponent), UPLOAD_BACKOFF_MAX_SECONDS);
+    Duration::seconds(seconds)
+}
+
+
+/// Return the paths of the rendered frames of a Task
+pub fn renderpaths(task: &Task) -> Vec<PathBuf>{
+    match task.command{
+        Command::Blender(ref b) => b.renderpaths(),
+        _ => Vec::new()
+    }
+}
+
+
+/// Post a single frame to the given URL as multipart form. Returns an error \
+/// if the request failed or the server didn't accept the frame
+pub fn post_frame<S, P>(client: &BenderClient, url: S, path: P) -> GenResult<()> where S: Into<String>, P: AsRef<Path>{
+    let url = url.into();
+    let form = reqwest::multipart::Form::new().file("file", path.as_ref())?;
+    // The multipart body is streamed, sign the frame it carries instead
+    let mut response = client.request_with_body_hash(Method::POST, url.as_str(), &sha256_hex(path.as_ref())?)
+                             .multipart(form)
+                             .send()?;
+    if response.status().is_success(){
+        Ok(())
+    }else{
+        let text = response.text().unwrap_or_else(|_| "Couldn't descramble response".to_string());
+        Err(From::from(format!("Server responded with {}: {}", response.status(), text.trim())))
+    }
+}
+
+
+
+
+/// Upload the frame at the given path, in chunks if it is larger than \
+/// `chunk_bytes` (and that isn't 0), in a single request otherwise. Falls \
+/// back to a single request if the server has no chunked endpoint
+pub fn upload_frame(client: &BenderClient, url: &str, path: &Path, chunk_bytes: u64) -> GenResult<()>{
+    let size = fs::metadata(path)?.len();
+    if chunk_bytes > 0 && size > chunk_bytes{
+        match chunked::upload_chunked(client, url, path, chunk_bytes){
+            Err(ref err) if err.is::<chunked::Unsupported>() => {
+                notemsg(format!("{}, posting {} in a single request", err, path.to_string_lossy()));
+                post_frame(client, url, path)
+            },
+            result => result
+        }
+    }else{
+        post_frame(client, url, path)
+    }
+}
+
+
+/// Upload all due frames of the given Task and update their upload state. \
+/// Marks all frames as uploaded in the Task once every single one made it. \
+/// Returns false if there was nothing to do
+pub fn upload_frames(task: &mut Task, sink: &FrameSink) -> bool{
+    let mut uploads = FrameUploads::from_task(task);
+    let due = uploads.due();
+    // A Task without any frames has nothing left to upload
+    if due.is_empty() && !uploads.frames.is_empty(){
+        return false;
+    }
+
+    println!("{}", format!(" @ [WORKER][{task_id}][{parent_id}][{short}] Upload started ({n} frame(s), {sink})",
+        task_id=&task.id[..6],
+        parent_id=&task.parent_id[..6],
+        short=task.command.short(),
+        n=due.len(),
+        sink=sink.describe()).blue());
+
+    for path in due.iter(){
+        match sink.deliver(task, path){
+            Ok(_) => uploads.set_uploaded(path),
+            Err(err) => {
+                errrun(format!("[{}][{}][{}] Couldn't upload frame {}: {}",
+                    &task.id[..6],
+                    &task.parent_id[..6],
+                    &task.command.short(),
+                    path.to_string_lossy(),
+                    err));
+                uploads.set_failed(path, format!("{}", err));
+            }
+        }
+    }
+    uploads.store(task);
+
+    if uploads.all_uploaded(){
+        println!("{}", format!(" ✔️ [WORKER][{task_id}][{parent_id}][{short}] Upload sucessful",
+            task_id=&task.id[..6],
+            parent_id=&task.parent_id[..6],
+            short=&task.command.short()).blue());
+        if let Command::Blender(ref mut b) = task.command{
+            if let Err(err) = b.set_all_uploaded(){
+                errrun(format!("[{}] Couldn't mark frames as uploaded: {}", &task.id[..6], err));
+            }
+        }
+    }else{
+        errrun(format!("[{}][{}][{}] Uploaded {} of {} frame(s), retrying the others later",
+            &task.id[..6],
+            &task.parent_id[..6],
+            &task.command.short(),
+            uploads.uploaded_count(),
+            uploads.frames.len()));
+    }
+    true
+}